@@ -0,0 +1,216 @@
+use crate::password;
+use crate::settings::Settings;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand::Rng;
+use std::time;
+
+/// The SASL mechanisms we support, in the order we advertise them.
+pub const MECHANISMS: &[&str] = &["PLAIN", "LOGIN", "CRAM-MD5"];
+
+/// The LOGIN mechanism's prompts, "Username:" and "Password:" base64 encoded.
+pub const LOGIN_USERNAME: &str = "VXNlcm5hbWU6";
+pub const LOGIN_PASSWORD: &str = "UGFzc3dvcmQ6";
+
+/// The credentials sent by a client with the PLAIN mechanism, RFC 4616.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Plain {
+    /// The identity the client wants to act as, empty to act as themselves.
+    pub authzid: String,
+    /// The identity whose password is being presented.
+    pub authcid: String,
+    pub password: String,
+}
+
+/// Decode a base64 encoded response from the client.
+pub fn decode(response: &str) -> Option<String> {
+    let decoded = base64::decode(response.trim()).ok()?;
+    String::from_utf8(decoded).ok()
+}
+
+/// Decode a base64 encoded PLAIN message, "[authzid] NUL authcid NUL passwd".
+pub fn decode_plain(response: &str) -> Option<Plain> {
+    let decoded = decode(response)?;
+    let mut parts = decoded.split('\0');
+
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(authzid), Some(authcid), Some(password), None)
+            if !authcid.is_empty() && !password.is_empty() =>
+        {
+            Some(Plain {
+                authzid: authzid.to_string(),
+                authcid: authcid.to_string(),
+                password: password.to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+// A hash to check the password against when the username is unknown, so that it takes
+// as long to fail as a wrong password does and usernames can't be found by timing us.
+lazy_static! {
+    static ref DUMMY_HASH: String = password::hash("").unwrap();
+}
+
+/// Check the username and password against the configured users.
+pub fn verify(settings: &Settings, username: &str, password: &str) -> bool {
+    match settings.user(username) {
+        Some(user) => password::verify(&user.password, password),
+        None => {
+            // Checking a plaintext password is quick, so only hash if the users' are.
+            if settings.users.iter().any(|user| password::is_hashed(&user.password)) {
+                password::verify(&DUMMY_HASH, password);
+            }
+            false
+        }
+    }
+}
+
+/// Check the PLAIN credentials against those configured.
+/// Returns the identity the client is now authenticated as.
+pub fn verify_plain(settings: &Settings, plain: &Plain) -> Option<String> {
+    // We don't allow anyone to act on behalf of another user.
+    if !plain.authzid.is_empty() && plain.authzid != plain.authcid {
+        return None;
+    }
+
+    if verify(settings, &plain.authcid, &plain.password) {
+        Some(plain.authcid.clone())
+    } else {
+        None
+    }
+}
+
+/// Generate a unique challenge for the CRAM-MD5 mechanism, RFC 2195.
+pub fn cram_md5_challenge(domain: &str) -> String {
+    let timestamp = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |n| n.as_secs());
+    format!("<{}.{}@{}>", rand::thread_rng().gen::<u32>(), timestamp, domain)
+}
+
+/// Decode a string of hex digits.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+/// CRAM-MD5 needs the actual password, so it is only offered if a user has one that
+/// hasn't been hashed.
+pub fn cram_md5_available(settings: &Settings) -> bool {
+    settings
+        .users
+        .iter()
+        .any(|user| !password::is_hashed(&user.password))
+}
+
+/// Check the client's response to a CRAM-MD5 challenge, "username digest".
+/// The digest is the HMAC-MD5 of the challenge keyed with the user's password, in hex.
+/// Returns the identity the client is now authenticated as.
+pub fn verify_cram_md5(settings: &Settings, challenge: &str, response: &str) -> Option<String> {
+    let (username, digest) = response.rsplit_once(' ')?;
+    let digest = decode_hex(digest)?;
+    let user = settings.user(username)?;
+
+    // The digest can only be checked if we know the actual password.
+    if password::is_hashed(&user.password) {
+        return None;
+    }
+
+    let mut mac = Hmac::<Md5>::new_from_slice(user.password.as_bytes()).ok()?;
+    mac.update(challenge.as_bytes());
+    mac.verify_slice(&digest).ok()?;
+    Some(username.to_string())
+}
+
+#[test]
+fn test_decode_plain() {
+    assert_eq!(
+        Some(Plain {
+            authzid: "".to_string(),
+            authcid: "user".to_string(),
+            password: "password".to_string(),
+        }),
+        decode_plain("AHVzZXIAcGFzc3dvcmQ=")
+    );
+    assert_eq!(
+        Some(Plain {
+            authzid: "admin".to_string(),
+            authcid: "user".to_string(),
+            password: "password".to_string(),
+        }),
+        decode_plain("YWRtaW4AdXNlcgBwYXNzd29yZA==")
+    );
+    assert_eq!(None, decode_plain("cGFzc3dvcmQ="));
+    assert_eq!(None, decode_plain("not base64!"));
+}
+
+#[test]
+fn test_verify_plain() {
+    let settings = Settings::default();
+    let plain = |authzid: &str, password: &str| Plain {
+        authzid: authzid.to_string(),
+        authcid: "user".to_string(),
+        password: password.to_string(),
+    };
+
+    assert_eq!(Some("user".to_string()), verify_plain(&settings, &plain("", "password")));
+    assert_eq!(Some("user".to_string()), verify_plain(&settings, &plain("user", "password")));
+    assert_eq!(None, verify_plain(&settings, &plain("admin", "password")));
+    assert_eq!(None, verify_plain(&settings, &plain("", "wrong")));
+}
+
+#[test]
+fn test_verify_unknown_user() {
+    let settings = Settings {
+        users: vec![crate::settings::User::new("user", &password::hash("password").unwrap())],
+        ..Settings::default()
+    };
+
+    assert!(verify(&settings, "user", "password"));
+    assert!(!verify(&settings, "nobody", "password"));
+    assert!(!verify(&settings, "nobody", ""));
+}
+
+#[test]
+fn test_verify_cram_md5() {
+    // The example exchange from RFC 2195.
+    let settings = Settings {
+        users: vec![crate::settings::User::new("tim", "tanstaaftanstaaf")],
+        ..Settings::default()
+    };
+    let challenge = "<1896.697170952@postoffice.reston.mci.net>";
+
+    assert_eq!(
+        Some("tim".to_string()),
+        verify_cram_md5(&settings, challenge, "tim b913a602c7eda7a495b4e6e7334d3890")
+    );
+    assert_eq!(
+        None,
+        verify_cram_md5(&settings, challenge, "tim b913a602c7eda7a495b4e6e7334d3891")
+    );
+    assert_eq!(None, verify_cram_md5(&settings, challenge, "tim"));
+}
+
+#[test]
+fn test_cram_md5_available() {
+    assert!(cram_md5_available(&Settings::default()));
+
+    let hashed = Settings {
+        users: vec![crate::settings::User::new("user", &password::hash("password").unwrap())],
+        ..Settings::default()
+    };
+    assert!(!cram_md5_available(&hashed));
+}
+
+#[test]
+fn test_cram_md5_challenge() {
+    let challenge = cram_md5_challenge("groove.com");
+
+    assert!(challenge.starts_with('<'));
+    assert!(challenge.ends_with("@groove.com>"));
+    assert_ne!(challenge, cram_md5_challenge("groove.com"));
+}
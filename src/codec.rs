@@ -0,0 +1,162 @@
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A line sent by the client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Line {
+    /// The bytes of the line exactly as they were sent, including the line ending,
+    /// CRLF or a bare LF. The last line has none if the connection closed part way
+    /// through it.
+    Text(Vec<u8>),
+    /// The line was longer than we allow, RFC 5321 4.5.3.1. It has been thrown away.
+    TooLong,
+}
+
+/// The line without its line ending.
+pub fn trim_ending(line: &[u8]) -> &[u8] {
+    match line.strip_suffix(b"\n") {
+        Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+        None => line,
+    }
+}
+
+/// Splits what the client sends into lines, leaving the bytes of each line untouched
+/// so message data can be kept exactly as it was sent.
+/// Lines longer than the maximum, including the line ending, are discarded rather than
+/// buffered. Our replies are written a line at a time.
+pub struct SmtpCodec {
+    // The longest line we accept, zero places no limit on the length.
+    max_line_length: usize,
+    // How far through the buffer we have already looked for a line ending.
+    searched: usize,
+    // The line being read is too long, so is thrown away up to its line ending.
+    discarding: bool,
+}
+
+impl SmtpCodec {
+    pub fn new(max_line_length: usize) -> Self {
+        SmtpCodec {
+            max_line_length,
+            searched: 0,
+            discarding: false,
+        }
+    }
+}
+
+impl Decoder for SmtpCodec {
+    type Item = Line;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match buf[self.searched..].iter().position(|b| *b == b'\n') {
+            Some(offset) => {
+                let end = self.searched + offset + 1;
+                self.searched = 0;
+                let line = buf.split_to(end);
+                if self.discarding || (self.max_line_length > 0 && end > self.max_line_length) {
+                    self.discarding = false;
+                    Ok(Some(Line::TooLong))
+                } else {
+                    Ok(Some(Line::Text(line.to_vec())))
+                }
+            }
+            None if self.max_line_length > 0 && buf.len() >= self.max_line_length => {
+                // The line ending is still to come, so the line is too long already.
+                self.discarding = true;
+                self.searched = 0;
+                buf.clear();
+                Ok(None)
+            }
+            None => {
+                self.searched = buf.len();
+                Ok(None)
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(line) => Ok(Some(line)),
+            None if buf.is_empty() => Ok(None),
+            // The connection closed part way through a line, take what there is.
+            None => {
+                self.searched = 0;
+                let line = buf.to_vec();
+                buf.advance(buf.len());
+                Ok(Some(Line::Text(line)))
+            }
+        }
+    }
+}
+
+impl Encoder<String> for SmtpCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, line: String, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        buf.reserve(line.len() + 1);
+        buf.put(line.as_bytes());
+        buf.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+#[test]
+fn test_decode() {
+    let mut codec = SmtpCodec::new(0);
+    let mut buf = BytesMut::from(&b"HELO ponk.com\r\nbare\nr\xe9sum\xe9\r\n\r\npart"[..]);
+
+    assert_eq!(
+        Some(Line::Text(b"HELO ponk.com\r\n".to_vec())),
+        codec.decode(&mut buf).unwrap()
+    );
+    assert_eq!(
+        Some(Line::Text(b"bare\n".to_vec())),
+        codec.decode(&mut buf).unwrap()
+    );
+    assert_eq!(
+        Some(Line::Text(b"r\xe9sum\xe9\r\n".to_vec())),
+        codec.decode(&mut buf).unwrap()
+    );
+    assert_eq!(
+        Some(Line::Text(b"\r\n".to_vec())),
+        codec.decode(&mut buf).unwrap()
+    );
+    assert_eq!(None, codec.decode(&mut buf).unwrap());
+
+    buf.extend_from_slice(b"ial\r");
+    assert_eq!(None, codec.decode(&mut buf).unwrap());
+    buf.extend_from_slice(b"\n");
+    assert_eq!(
+        Some(Line::Text(b"partial\r\n".to_vec())),
+        codec.decode(&mut buf).unwrap()
+    );
+    assert!(buf.is_empty());
+
+    assert_eq!(b"partial", trim_ending(b"partial\r\n"));
+    assert_eq!(b"bare", trim_ending(b"bare\n"));
+    assert_eq!(b"cr\r", trim_ending(b"cr\r"));
+}
+
+#[test]
+fn test_decode_too_long() {
+    let mut codec = SmtpCodec::new(8);
+    let mut buf = BytesMut::from(&b"123456\r\n1234567\r\n"[..]);
+
+    assert_eq!(
+        Some(Line::Text(b"123456\r\n".to_vec())),
+        codec.decode(&mut buf).unwrap()
+    );
+    assert_eq!(Some(Line::TooLong), codec.decode(&mut buf).unwrap());
+
+    // A long line isn't kept while waiting for its line ending.
+    buf.extend_from_slice(b"12345678");
+    assert_eq!(None, codec.decode(&mut buf).unwrap());
+    assert!(buf.is_empty());
+    buf.extend_from_slice(b"9\r\nOK\n");
+    assert_eq!(Some(Line::TooLong), codec.decode(&mut buf).unwrap());
+    assert_eq!(
+        Some(Line::Text(b"OK\n".to_vec())),
+        codec.decode(&mut buf).unwrap()
+    );
+}
@@ -1,17 +1,38 @@
 use regex::{Regex, RegexBuilder};
+use std::str::FromStr;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Eq, Debug)]
 pub enum Command {
     EHLO(String),
     HELO(String),
-    MAIL(String),
-    RCPT(String),
-    AUTH(String),   // TODO Auth method really should be a string.
+    MAIL(String, Vec<String>), // The address and any ESMTP parameters.
+    RCPT(String, Vec<String>),
+    AUTH(String, Option<String>), // The mechanism and any initial response.
     DATA,
     RSET,
     NOOP,
     QUIT,
     VRFY(String),
+    STARTTLS,
+}
+
+/// The reasons a line from the client could not be parsed into a command.
+#[derive(PartialEq, Eq, Debug)]
+pub enum ParseError {
+    /// We have no idea what the command is.
+    Unrecognized,
+    /// The command is a valid SMTP command that we don't support.
+    NotImplemented,
+    /// The command is recognized, but its arguments are not valid.
+    InvalidParameters,
+    /// The line is longer than we allow.
+    TooLong,
+}
+
+/// The ESMTP parameters following the address in MAIL and RCPT commands, "KEYWORD[=VALUE]".
+fn parameters(parameters: &str) -> Vec<String> {
+    parameters.split_whitespace().map(|parameter| parameter.to_string()).collect()
 }
 
 /// Build a case insensitive regex.
@@ -24,56 +45,77 @@ fn regex(re: &str) -> Regex {
 
 // Setup our regexes in advance.
 lazy_static! {
-    static ref EHLO: Regex = regex(r"EHLO");
-    static ref HELO: Regex = regex(r"HELO");
-    static ref AUTH: Regex = regex(r"AUTH\s*(.*)\s*");
-    static ref MAIL: Regex = regex(r"MAIL FROM\s*:\s*<(.*)>");
-    static ref RCPT: Regex = regex(r"RCPT TO\s*:\s*<(.*)>");
-    static ref DATA: Regex = regex(r"DATA");
-    static ref RSET: Regex = regex(r"RSET");
-    static ref NOOP: Regex = regex(r"NOOP");
-    static ref QUIT: Regex = regex(r"QUIT");
-    static ref VRFY: Regex = regex(r"VRFY\s*:\s*<(.*)>");
+    static ref VERB: Regex = regex(r"^\s*([a-z]+)(\s+.*)?$");
+    static ref MAIL: Regex = regex(r"^MAIL FROM\s*:\s*<([^>]*)>(.*)$");
+    static ref RCPT: Regex = regex(r"^RCPT TO\s*:\s*<([^>]*)>(.*)$");
+    static ref VRFY: Regex = regex(r"^VRFY\s*:?\s*<?([^<>]+)>?\s*$");
 }
 
-impl Command {
+impl FromStr for Command {
+    type Err = ParseError;
+
     /// Parses the message from the client.
-    pub fn from_str(text: &str) -> Option<Self> {
-        if EHLO.is_match(text) {
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let capture = VERB.captures(text).ok_or(ParseError::Unrecognized)?;
+        let verb = capture.get(1).unwrap().as_str().to_uppercase();
+        let arguments = capture.get(2).map_or("", |arguments| arguments.as_str().trim());
+
+        // The arguments end up in the headers we add, a control character such as a bare
+        // CR would let the client add headers of their own.
+        if arguments.chars().any(|c| c.is_control() && c != '\t') {
+            return Err(ParseError::InvalidParameters);
+        }
+
+        match verb.as_str() {
             // Extended HELLO message.
-            let domain = EHLO.replace(text, "");
-            Some(Command::EHLO(domain.trim().to_string()))
-        } else if HELO.is_match(text) {
+            "EHLO" => Ok(Command::EHLO(arguments.to_string())),
             // Normal HELLO message.
-            let domain = HELO.replace(text, "");
-            Some(Command::HELO(domain.trim().to_string()))
-        } else if let Some(capture) = MAIL.captures(text) {
-            // Initiate the message transaction with the address of the sender.
-            let from = capture.get(1).unwrap().as_str();
-            Some(Command::MAIL(from.trim().to_string()))
-        } else if let Some(capture) = RCPT.captures(text) {
-            // Recipients of the message.
-            // TODO Similarly, relay hosts SHOULD strip or ignore source routes, and
-            // names MUST NOT be copied into the reverse-path. 
-            let to = capture.get(1).unwrap().as_str();
-            Some(Command::RCPT(to.trim().to_string()))
-        } else if let Some(capture) = AUTH.captures(text) {
-            let method = capture.get(1).unwrap().as_str();
-            Some(Command::AUTH(method.trim().to_string()))  
-        } else if DATA.is_match(text) {
-            Some(Command::DATA)
-        } else if RSET.is_match(text) {
-            Some(Command::RSET)
-        } else if NOOP.is_match(text) {
-            Some(Command::NOOP)
-        } else if QUIT.is_match(text) {
-            Some(Command::QUIT)
-        } else if let Some(capture) = VRFY.captures(text) {
-            // Mailbox to verify.
-            let addr = capture.get(1).unwrap().as_str();
-            Some(Command::VRFY(addr.trim().to_string()))
-        } else {
-            None
+            "HELO" => Ok(Command::HELO(arguments.to_string())),
+            "MAIL" => {
+                // Initiate the message transaction with the address of the sender.
+                let capture = MAIL.captures(text).ok_or(ParseError::InvalidParameters)?;
+                let from = capture.get(1).unwrap().as_str();
+                Ok(Command::MAIL(
+                    from.trim().to_string(),
+                    parameters(capture.get(2).unwrap().as_str()),
+                ))
+            }
+            "RCPT" => {
+                // Recipients of the message.
+                // TODO Similarly, relay hosts SHOULD strip or ignore source routes, and
+                // names MUST NOT be copied into the reverse-path.
+                let capture = RCPT.captures(text).ok_or(ParseError::InvalidParameters)?;
+                let to = capture.get(1).unwrap().as_str();
+                Ok(Command::RCPT(
+                    to.trim().to_string(),
+                    parameters(capture.get(2).unwrap().as_str()),
+                ))
+            }
+            "AUTH" => {
+                let mut arguments = arguments.split_whitespace();
+                match (arguments.next(), arguments.next(), arguments.next()) {
+                    (Some(mechanism), response, None) => Ok(Command::AUTH(
+                        mechanism.to_uppercase(),
+                        response.map(|response| response.to_string()),
+                    )),
+                    _ => Err(ParseError::InvalidParameters),
+                }
+            }
+            "DATA" if arguments.is_empty() => Ok(Command::DATA),
+            "RSET" if arguments.is_empty() => Ok(Command::RSET),
+            // NOOP may carry a string parameter, which is ignored.
+            "NOOP" => Ok(Command::NOOP),
+            "QUIT" if arguments.is_empty() => Ok(Command::QUIT),
+            "STARTTLS" if arguments.is_empty() => Ok(Command::STARTTLS),
+            "VRFY" => {
+                // Mailbox to verify.
+                let capture = VRFY.captures(text).ok_or(ParseError::InvalidParameters)?;
+                let addr = capture.get(1).unwrap().as_str();
+                Ok(Command::VRFY(addr.trim().to_string()))
+            }
+            "DATA" | "RSET" | "QUIT" | "STARTTLS" => Err(ParseError::InvalidParameters),
+            "EXPN" | "HELP" | "TURN" | "SEND" | "SOML" | "SAML" => Err(ParseError::NotImplemented),
+            _ => Err(ParseError::Unrecognized),
         }
     }
 }
@@ -81,11 +123,50 @@ impl Command {
 #[test]
 fn test_mail_command() {
     let command = Command::from_str("MAIL FROM: <ook@onk.com>");
-    assert_eq!(Some(Command::MAIL("ook@onk.com".to_string())), command);
+    assert_eq!(Ok(Command::MAIL("ook@onk.com".to_string(), vec![])), command);
+
+    let command = Command::from_str("MAIL FROM:<ook@onk.com> SIZE=1000  BODY=8BITMIME");
+    assert_eq!(
+        Ok(Command::MAIL(
+            "ook@onk.com".to_string(),
+            vec!["SIZE=1000".to_string(), "BODY=8BITMIME".to_string()]
+        )),
+        command
+    );
 }
 
 #[test]
 fn test_rcpt_command() {
     let command = Command::from_str("RCPT TO: <ook@onk.com>");
-    assert_eq!(Some(Command::RCPT("ook@onk.com".to_string())), command);
+    assert_eq!(Ok(Command::RCPT("ook@onk.com".to_string(), vec![])), command);
+}
+
+#[test]
+fn test_auth_command() {
+    let command = Command::from_str("AUTH plain");
+    assert_eq!(Ok(Command::AUTH("PLAIN".to_string(), None)), command);
+
+    let command = Command::from_str("AUTH PLAIN AHVzZXIAcGFzc3dvcmQ=");
+    assert_eq!(
+        Ok(Command::AUTH("PLAIN".to_string(), Some("AHVzZXIAcGFzc3dvcmQ=".to_string()))),
+        command
+    );
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(Err(ParseError::Unrecognized), Command::from_str("WIBBLE"));
+    assert_eq!(Err(ParseError::NotImplemented), Command::from_str("EXPN staff"));
+    assert_eq!(Err(ParseError::InvalidParameters), Command::from_str("MAIL FROM ook@onk.com"));
+    assert_eq!(Err(ParseError::InvalidParameters), Command::from_str("DATA now"));
+    assert_eq!(Err(ParseError::InvalidParameters), Command::from_str("AUTH"));
+    assert_eq!(
+        Err(ParseError::InvalidParameters),
+        Command::from_str("HELO ponk.com\rX-Injected: yes")
+    );
+    assert_eq!(Err(ParseError::InvalidParameters), Command::from_str("EHLO ponk\0.com"));
+    assert_eq!(
+        Err(ParseError::InvalidParameters),
+        Command::from_str("MAIL FROM:<ook@onk.com\r>")
+    );
 }
@@ -1,26 +1,38 @@
-use std::{env, net, path, time};
+use futures::future;
+use socket2::{Domain, Socket, Type};
+use std::sync::Arc;
+use std::{env, io, net, path, process};
 use tokio::{net::TcpListener, stream::StreamExt};
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio_rustls::TlsAcceptor;
 
 #[macro_use]
 extern crate lazy_static;
 
+mod auth;
+mod codec;
 mod commands;
 mod message;
+mod password;
 mod responses;
 mod settings;
+mod sink;
 mod smtp;
+mod storage;
+mod tls;
 
-/// Get the address to listen to.
-fn get_listen_address(protocol: u8, port: u16) -> net::SocketAddr {
-    match protocol {
-        4 => net::SocketAddr::new(net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0)), port),
-        6 => net::SocketAddr::new(
-            net::IpAddr::V6(net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
-            port,
-        ),
-        _ => panic!("Protocol must be either 4 or 6"),
+/// Bind a socket to the address.
+/// IPv6 sockets only accept IPv6 connections, so they don't clash with IPv4 sockets on
+/// the same port.
+fn bind(addr: net::SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
     }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
 }
 
 /// Load the settings from the file specified in the first argument.
@@ -34,41 +46,75 @@ fn load_settings() -> Result<settings::Settings, Box<dyn std::error::Error>> {
     }
 }
 
+/// Print the hash of a password, ready to be pasted into the settings.
+/// The password is read from stdin if it isn't given as an argument,
+/// so it doesn't need to end up in the shell history.
+fn hash_password(password: Option<&String>) {
+    let password = match password {
+        Some(password) => password.clone(),
+        None => {
+            let mut line = String::new();
+            if let Err(e) = io::stdin().read_line(&mut line) {
+                eprintln!("Unable to read the password {}", e);
+                process::exit(1);
+            }
+            line.trim_end_matches(&['\r', '\n'][..]).to_string()
+        }
+    };
+
+    match password::hash(&password) {
+        Ok(hash) => println!("{}", hash),
+        Err(e) => {
+            eprintln!("Unable to hash the password {}", e);
+            process::exit(1);
+        }
+    }
+}
+
 // Load settings from a toml file if it has beet specified.
 // Else use the defaults.
 lazy_static! {
     static ref SETTINGS: settings::Settings = load_settings().unwrap();
 }
 
-/// The main function.
-/// Sets up the socket and handles incoming requests.
-#[tokio::main]
-async fn main() {
-    // Setup the socket.
-    let addr = get_listen_address(SETTINGS.protocol, SETTINGS.port);
-    let mut listener = TcpListener::bind(&addr).await.unwrap();
-
-    println!("Listenig on port {} v{}", SETTINGS.port, SETTINGS.protocol);
+// Submission requires clients to authenticate before sending mail.
+lazy_static! {
+    static ref SUBMISSION_SETTINGS: settings::Settings = settings::Settings {
+        require_auth: true,
+        ..SETTINGS.clone()
+    };
+}
 
+/// Listen for connections on the given address, holding a conversation with each.
+/// The acceptor encrypts connections, if TLS has been configured.
+async fn listen(
+    mut listener: TcpListener,
+    settings: &'static settings::Settings,
+    role: settings::Role,
+    acceptor: Option<TlsAcceptor>,
+    sink: Arc<dyn sink::MessageSink>,
+) {
     while let Some(stream) = listener.next().await {
+        let sink = sink.clone();
+        let acceptor = acceptor.clone();
         tokio::spawn(async move {
+            // Plain and submission sockets offer STARTTLS if they can.
+            let tls = match (role, acceptor.as_ref()) {
+                (settings::Role::Tls, Some(acceptor)) => smtp::Tls::Implicit(acceptor),
+                (_, Some(acceptor)) => smtp::Tls::Upgrade(acceptor),
+                (_, None) => smtp::Tls::Disabled,
+            };
             match stream {
                 Ok(stream) => {
                     println!("New connection!");
-                    let framed = Framed::new(stream, LinesCodec::new());
-                    let message = smtp::converse(framed, &SETTINGS).await.unwrap();
-                    let now = time::SystemTime::now();
-                    match now.duration_since(time::SystemTime::UNIX_EPOCH) {
-                        Ok(n) => {
-                            message
-                                .save_to_file(format!("./received/{}.eml", n.as_millis()))
-                                .await
-                                .unwrap();
-                        }
-                        Err(_) => {
-                            // TODO Insert some kind of McFly joke...
-                            eprintln!("We have gone back in time!");
-                        }
+                    let deliver = |message| {
+                        let sink = sink.clone();
+                        async move { sink.deliver(&message).await }
+                    };
+                    let peer = stream.peer_addr().ok();
+                    let result = smtp::converse(stream, peer, settings, tls, deliver).await;
+                    if let Err(e) = result {
+                        eprintln!("Connection ended {}", e);
                     }
                 }
                 Err(e) => {
@@ -78,3 +124,92 @@ async fn main() {
         });
     }
 }
+
+/// The main function.
+/// Sets up the socket and handles incoming requests.
+#[tokio::main]
+async fn main() {
+    let args: Vec<_> = env::args().collect();
+    if args.len() > 1 && args[1] == "hash-password" {
+        hash_password(args.get(2));
+        return;
+    }
+
+    let sink: Arc<dyn sink::MessageSink> = match sink::open(&SETTINGS) {
+        Ok(sink) => sink.into(),
+        Err(e) => {
+            eprintln!("Unable to set up delivery {}", e);
+            process::exit(1);
+        }
+    };
+
+    let acceptor = match tls::acceptor(&SETTINGS) {
+        Ok(acceptor) => acceptor,
+        Err(e) => {
+            eprintln!("Unable to set up TLS {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut listeners = Vec::new();
+
+    for listen_on in &SETTINGS.listen {
+        if listen_on.role == settings::Role::Tls && acceptor.is_none() {
+            eprintln!(
+                "TLS must be enabled to listen on {} with the tls role",
+                listen_on.address
+            );
+            process::exit(1);
+        }
+        let settings = match listen_on.role {
+            settings::Role::Submission => &*SUBMISSION_SETTINGS,
+            _ => &*SETTINGS,
+        };
+
+        match bind(listen_on.address) {
+            Ok(listener) => {
+                println!("Listening on {} ({:?})", listen_on.address, listen_on.role);
+                listeners.push(tokio::spawn(listen(
+                    listener,
+                    settings,
+                    listen_on.role,
+                    acceptor.clone(),
+                    sink.clone(),
+                )));
+            }
+            Err(e) if listen_on.optional => {
+                eprintln!("Not listening on {} {}", listen_on.address, e);
+            }
+            Err(e) => {
+                eprintln!("Unable to listen on {} {}", listen_on.address, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if listeners.is_empty() {
+        eprintln!("Not listening on any address");
+        process::exit(1);
+    }
+
+    for listener in future::join_all(listeners).await {
+        listener.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_bind() {
+    let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = listener.local_addr().unwrap();
+    assert!(bind(address).is_err());
+
+    // IPv6 sockets leave the IPv4 port free, on hosts with IPv6.
+    let any = net::SocketAddr::new(net::Ipv4Addr::UNSPECIFIED.into(), 0);
+    let listener = bind(any).unwrap();
+    let mut v6 = listener.local_addr().unwrap();
+    v6.set_ip(net::Ipv6Addr::UNSPECIFIED.into());
+    match bind(v6) {
+        Ok(listener) => assert!(listener.local_addr().unwrap().is_ipv6()),
+        Err(e) => assert_ne!(io::ErrorKind::AddrInUse, e.kind()),
+    }
+}
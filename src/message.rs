@@ -1,36 +1,343 @@
-use std::path::Path;
-use tokio::fs;
-use tokio::io;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_derive::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time;
+
+/// Counts the messages received, so no two get the same id.
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+/// Generate an id for a message that is unique across every message we have received,
+/// "seconds.microseconds.process.count".
+pub fn unique_id() -> String {
+    let now = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.{:06}.{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        process::id(),
+        RECEIVED.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// What was negotiated when the connection was encrypted.
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsInfo {
+    pub version: String,
+    pub cipher: String,
+    /// The identity from the certificate the client presented, if any.
+    pub client_certificate: Option<String>,
+}
+
+/// An address from the envelope along with the ESMTP parameters given with it.
+#[derive(Serialize)]
+struct Address<'a> {
+    address: &'a str,
+    parameters: &'a [String],
+}
+
+/// The envelope of the message as saved alongside it.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    id: &'a str,
+    received: String,
+    mail_from: Address<'a>,
+    rcpt_to: Vec<Address<'a>>,
+    helo: Option<&'a str>,
+    client: Option<String>,
+    protocol: &'a str,
+    authenticated: Option<&'a str>,
+    tls: Option<&'a TlsInfo>,
+}
 
 #[derive(Debug, Clone)]
 pub struct Message {
     pub from: Option<String>,
+    /// The ESMTP parameters given with MAIL FROM.
+    pub from_parameters: Vec<String>,
     pub to: Vec<String>,
-    pub data: Vec<String>,
-    pub saved: Option<String>,
+    /// The ESMTP parameters given with each RCPT TO, in the same order as `to`.
+    pub to_parameters: Vec<Vec<String>>,
+    /// The data exactly as the client sent it, less the dots added for transparency.
+    /// Every line ends in CRLF, a bare LF from the client is made CRLF.
+    pub data: Vec<u8>,
+    /// The user the client had authenticated as when the message was sent.
+    pub authenticated: Option<String>,
+    /// The address of the client that sent the message.
+    pub peer: Option<SocketAddr>,
+    /// When the message was received, set once all of its data has arrived.
+    pub received: DateTime<Utc>,
+    /// Identifies the message in our logs and trace headers.
+    pub id: String,
+    /// The name the client gave in their HELO or EHLO.
+    pub helo: Option<String>,
+    /// How the message was received, as given in the Received header, RFC 3848.
+    pub protocol: String,
+    /// Our domain that received the message.
+    pub received_by: String,
+    /// How the connection was encrypted, if it was.
+    pub tls: Option<TlsInfo>,
 }
 
 impl Message {
     pub fn new() -> Self {
         Message {
             from: None,
+            from_parameters: Vec::new(),
             to: Vec::new(),
+            to_parameters: Vec::new(),
             data: Vec::new(),
-            saved: None,
+            authenticated: None,
+            peer: None,
+            received: Utc::now(),
+            id: unique_id(),
+            helo: None,
+            protocol: "SMTP".to_string(),
+            received_by: String::new(),
+            tls: None,
+        }
+    }
+
+    /// The Received trace header recording how we got the message, RFC 5321 4.4.
+    /// It is folded over several lines.
+    pub fn received_header(&self) -> Vec<String> {
+        let literal = self.peer.map(|peer| match peer.ip() {
+            IpAddr::V4(ip) => format!("[{}]", ip),
+            IpAddr::V6(ip) => format!("[IPv6:{}]", ip),
+        });
+        // Without a name from HELO the client's address takes its place.
+        let mut from = match (self.helo.as_deref(), &literal) {
+            (Some(helo), _) if !helo.is_empty() => helo.to_string(),
+            (_, Some(literal)) => literal.clone(),
+            _ => "unknown".to_string(),
+        };
+        if let Some(literal) = &literal {
+            from.push_str(&format!(" ({})", literal));
+        }
+
+        vec![
+            format!("Received: from {}", from),
+            format!(
+                "\tby {} (smteepee) with {} id {};",
+                self.received_by, self.protocol, self.id
+            ),
+            format!("\t{}", self.received.to_rfc2822()),
+        ]
+    }
+
+    /// The Return-Path header recording the envelope sender, added as the message is
+    /// finally delivered.
+    pub fn return_path_header(&self) -> String {
+        format!("Return-Path: <{}>", self.from.as_deref().unwrap_or_default())
+    }
+
+    /// The envelope as JSON, everything we know about how the message was sent.
+    pub fn envelope_json(&self) -> String {
+        let envelope = Envelope {
+            id: &self.id,
+            received: self.received.to_rfc3339_opts(SecondsFormat::Millis, true),
+            mail_from: Address {
+                address: self.from.as_deref().unwrap_or_default(),
+                parameters: &self.from_parameters,
+            },
+            rcpt_to: self
+                .to
+                .iter()
+                .enumerate()
+                .map(|(idx, to)| Address {
+                    address: to,
+                    parameters: self.to_parameters.get(idx).map_or(&[][..], |p| p.as_slice()),
+                })
+                .collect(),
+            helo: self.helo.as_deref(),
+            client: self.peer.map(|peer| peer.to_string()),
+            protocol: &self.protocol,
+            authenticated: self.authenticated.as_deref(),
+            tls: self.tls.as_ref(),
+        };
+        // Serializing plain structs with string keys can't fail.
+        serde_json::to_string_pretty(&envelope).unwrap()
+    }
+
+    /// The envelope as a block of X-Envelope headers, one X-Envelope-To for each recipient.
+    pub fn envelope_headers(&self) -> Vec<String> {
+        self.envelope_headers_for(|_| true)
+    }
+
+    /// The envelope headers with an X-Envelope-To only for the recipients, by their index,
+    /// that are included. A copy of the message just for some recipients mustn't reveal
+    /// who else it went to.
+    pub fn envelope_headers_for(&self, included: impl Fn(usize) -> bool) -> Vec<String> {
+        /// The address followed by any parameters.
+        fn address(address: &str, parameters: &[String]) -> String {
+            let mut value = format!("<{}>", address);
+            for parameter in parameters {
+                value.push(' ');
+                value.push_str(parameter);
+            }
+            value
+        }
+
+        let mut headers = vec![format!(
+            "X-Envelope-From: {}",
+            address(self.from.as_deref().unwrap_or_default(), &self.from_parameters)
+        )];
+        for (idx, to) in self.to.iter().enumerate().filter(|(idx, _)| included(*idx)) {
+            let parameters = self.to_parameters.get(idx).map_or(&[][..], |p| p.as_slice());
+            headers.push(format!("X-Envelope-To: {}", address(to, parameters)));
+        }
+        if let Some(helo) = &self.helo {
+            headers.push(format!("X-Envelope-Helo: {}", helo));
         }
+        if let Some(peer) = &self.peer {
+            headers.push(format!("X-Envelope-Client: {}", peer));
+        }
+        if let Some(authenticated) = &self.authenticated {
+            headers.push(format!("X-Envelope-Auth: {}", authenticated));
+        }
+        if let Some(tls) = &self.tls {
+            let mut value = format!("{} {}", tls.version, tls.cipher);
+            if let Some(certificate) = &tls.client_certificate {
+                value.push_str(&format!(" client={}", certificate));
+            }
+            headers.push(format!("X-Envelope-Tls: {}", value));
+        }
+        headers
     }
-    
-    pub fn get_data(&self) -> String {
-        self.data.join("\n")
+
+    /// The data with the header lines added before it.
+    pub fn with_headers(&self, headers: &[String]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.data.len() + 256);
+        for header in headers {
+            data.extend_from_slice(header.as_bytes());
+            data.extend_from_slice(b"\r\n");
+        }
+        data.extend_from_slice(&self.data);
+        data
+    }
+
+    /// The message as it is delivered, our trace headers followed by the data the client sent.
+    pub fn get_data(&self) -> Vec<u8> {
+        let mut headers = vec![self.return_path_header()];
+        headers.extend(self.received_header());
+        self.with_headers(&headers)
     }
 
-    /// Save the data of the message to a file at the given path.
-    /// Passes the message along to the next future.
-    pub async fn save_to_file<P>(self, path: P) -> io::Result<()>
-    where
-        P: AsRef<Path> + Send + Clone + 'static,
-    {
-        fs::write(path, self.get_data()).await
+    /// The size of the message in bytes as it was sent, including the line endings.
+    pub fn size(&self) -> usize {
+        self.data.len()
     }
-    
+
+    /// The value of the first header with the given name, with any folding undone.
+    pub fn header(&self, name: &str) -> Option<String> {
+        let lines = self
+            .data
+            .split(|b| *b == b'\n')
+            .map(|line| String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)));
+
+        let mut value: Option<String> = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let folded = line.starts_with(' ') || line.starts_with('\t');
+            match value {
+                Some(ref mut value) if folded => value.push_str(&line),
+                Some(value) => return Some(value.trim().to_string()),
+                None if folded => (),
+                None => {
+                    value = line
+                        .split_once(':')
+                        .filter(|(field, _)| field.trim().eq_ignore_ascii_case(name))
+                        .map(|(_, value)| value.to_string())
+                }
+            }
+        }
+        value.map(|value| value.trim().to_string())
+    }
+
+}
+
+#[test]
+fn test_unique_id() {
+    assert_ne!(unique_id(), unique_id());
+}
+
+#[test]
+fn test_header() {
+    let mut message = Message::new();
+    message.data = b"From: ook@onk.com\r\n\
+        subject: A subject\r\n  that is folded\r\n\
+        Message-ID: <1@onk.com>\r\n\
+        \r\n\
+        Subject: Not a header\r\n"
+        .to_vec();
+
+    assert_eq!(Some("A subject  that is folded".to_string()), message.header("Subject"));
+    assert_eq!(Some("<1@onk.com>".to_string()), message.header("message-id"));
+    assert_eq!(None, message.header("To"));
+}
+
+#[test]
+fn test_trace_headers() {
+    let mut message = Message::new();
+    message.from = Some("ook@onk.com".to_string());
+    message.helo = Some("ponk.com".to_string());
+    message.peer = Some("[2001:db8::1]:2525".parse().unwrap());
+    message.protocol = "ESMTPSA".to_string();
+    message.received_by = "groove.com".to_string();
+    message.received = DateTime::parse_from_rfc3339("2020-02-02T12:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    message.data = b"Subject: Ook\r\n".to_vec();
+
+    assert_eq!(
+        format!(
+            "Return-Path: <ook@onk.com>\r\n\
+             Received: from ponk.com ([IPv6:2001:db8::1])\r\n\
+             \tby groove.com (smteepee) with ESMTPSA id {};\r\n\
+             \tSun, 2 Feb 2020 12:00:00 +0000\r\n\
+             Subject: Ook\r\n",
+            message.id
+        )
+        .into_bytes(),
+        message.get_data()
+    );
+
+    // A HELO without a name gives the client's address instead.
+    message.helo = Some(String::new());
+    message.peer = Some("192.0.2.1:2525".parse().unwrap());
+    assert_eq!(
+        "Received: from [192.0.2.1] ([192.0.2.1])",
+        message.received_header()[0]
+    );
+    message.peer = None;
+    assert_eq!("Received: from unknown", message.received_header()[0]);
+}
+
+#[test]
+fn test_envelope() {
+    let mut message = Message::new();
+    message.from = Some("ook@onk.com".to_string());
+    message.from_parameters = vec!["SIZE=10".to_string()];
+    message.to = vec!["pook@ook.co.uk".to_string(), "bcc@ponk.com".to_string()];
+    message.to_parameters = vec![vec![], vec!["NOTIFY=NEVER".to_string()]];
+    message.helo = Some("ponk.com".to_string());
+    message.authenticated = Some("user".to_string());
+
+    assert_eq!(
+        vec![
+            "X-Envelope-From: <ook@onk.com> SIZE=10".to_string(),
+            "X-Envelope-To: <pook@ook.co.uk>".to_string(),
+            "X-Envelope-To: <bcc@ponk.com> NOTIFY=NEVER".to_string(),
+            "X-Envelope-Helo: ponk.com".to_string(),
+            "X-Envelope-Auth: user".to_string(),
+        ],
+        message.envelope_headers()
+    );
+
+    let envelope: serde_json::Value = serde_json::from_str(&message.envelope_json()).unwrap();
+    assert_eq!("ook@onk.com", envelope["mail_from"]["address"]);
+    assert_eq!("NOTIFY=NEVER", envelope["rcpt_to"][1]["parameters"][0]);
+    assert_eq!(serde_json::Value::Null, envelope["tls"]);
 }
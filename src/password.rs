@@ -0,0 +1,58 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use subtle::ConstantTimeEq;
+
+/// Is the stored password a PHC string from one of the hashing schemes we support?
+/// Anything else is taken to be a plaintext password.
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with("$argon2") || stored.starts_with("$2")
+}
+
+/// Hash the password with argon2, giving a PHC string to store in the settings.
+pub fn hash(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())?;
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// Check the password given by a client against the stored password.
+/// The stored password is either an argon2 or bcrypt PHC string, or plaintext.
+/// Each is compared in constant time.
+pub fn verify(stored: &str, password: &str) -> bool {
+    if stored.starts_with("$argon2") {
+        PasswordHash::new(stored)
+            .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+            .is_ok()
+    } else if stored.starts_with("$2") {
+        bcrypt::verify(password, stored).unwrap_or(false)
+    } else {
+        stored.as_bytes().ct_eq(password.as_bytes()).into()
+    }
+}
+
+#[test]
+fn test_argon2() {
+    let hashed = hash("password").unwrap();
+
+    assert!(hashed.starts_with("$argon2id$"));
+    assert!(is_hashed(&hashed));
+    assert!(verify(&hashed, "password"));
+    assert!(!verify(&hashed, "wrong"));
+}
+
+#[test]
+fn test_bcrypt() {
+    let hashed = bcrypt::hash("password", 4).unwrap();
+
+    assert!(is_hashed(&hashed));
+    assert!(verify(&hashed, "password"));
+    assert!(!verify(&hashed, "wrong"));
+}
+
+#[test]
+fn test_plaintext() {
+    assert!(!is_hashed("password"));
+    assert!(verify("password", "password"));
+    assert!(!verify("password", "passwore"));
+    assert!(!verify("password", "pass"));
+}
@@ -3,12 +3,14 @@ pub enum Response<'a> {
     _211_SystemStatus,
     _214_Help,
     _220_ServiceReady(&'a str),
+    _220_ReadyToStartTls,
     _221_ServiceClosing,
     _235_AuthenticationSuccessful,
     _250_Completed(&'a str),
+    _250_Extensions(&'a str, &'a [String]), // EHLO greeting followed by the supported extensions
     _251_UserNotLocal,
     _252_CannotVRFYuser, // but will accept message and attempt delivery
-    _334_Authenticate,
+    _334_Authenticate(&'a str), // base64 encoded challenge
     _354_StartMailInput, // end with <CRLF>.<CRLF>
     _421_ServiceNotAvailable(&'a str),
     _450_MailboxUnavailable,
@@ -16,20 +18,48 @@ pub enum Response<'a> {
     _452_InsufficientStorage,
     _455_ServerUnableToAccommodate,
     _500_SyntaxError, // command unrecognized
+    _500_LineTooLong,
     _501_SyntaxErrorInParameters,
     _502_CommandNotImplemented,
     _503_BadSequence,
     _504_ParameterNotImplemented,
+    _530_AuthenticationRequired,
     _535_FailedAuthentication,
+    _538_EncryptionRequired,
     _550_MailboxUnavailable,
     _551_UserNotLocal, // please try <forward-path> (See Section 3.4)
     _552_ExceededStorageAllocation,
+    _552_MessageSizeExceeded, // the size declared with MAIL FROM, RFC 1870
     _553_MailboxNameNotAllowed,
-    _554_TransactionFailed,
+    _554_TransactionFailed(&'a str),
     _555_ParametersNotRecognized, // MAIL FROM/RCPT TO
 }
 
+/// Format a multi-line reply, each line but the last has a hyphen following the code.
+fn multiline(code: u16, lines: &[&str]) -> Vec<String> {
+    lines
+        .iter()
+        .enumerate()
+        .map(|(idx, line)| {
+            let separator = if idx + 1 == lines.len() { ' ' } else { '-' };
+            format!("{}{}{}", code, separator, line)
+        })
+        .collect()
+}
+
 impl<'a> Response<'a> {
+    /// The lines making up the response.
+    pub fn as_lines(&self) -> Vec<String> {
+        match self {
+            Response::_250_Extensions(greeting, extensions) => {
+                let mut lines = vec![*greeting];
+                lines.extend(extensions.iter().map(|extension| extension.as_str()));
+                multiline(250, &lines)
+            }
+            _ => vec![self.as_string()],
+        }
+    }
+
     pub fn as_string(&self) -> String {
         match self {
             Response::_211_SystemStatus => "211".to_string(),
@@ -37,29 +67,53 @@ impl<'a> Response<'a> {
             Response::_220_ServiceReady(domain) => {
                 format!("220 local ESMTP {} Service Ready", domain)
             }
+            Response::_220_ReadyToStartTls => "220 Ready to start TLS".to_string(),
             Response::_221_ServiceClosing => "221 Bye".to_string(),
             Response::_235_AuthenticationSuccessful => "235 Authentication successful".to_string(),
             Response::_250_Completed(greeting) => format!("250 {}", greeting),
+            Response::_250_Extensions(..) => self.as_lines().join("\n"),
             Response::_251_UserNotLocal => "251".to_string(),
             Response::_252_CannotVRFYuser => "252".to_string(),
-            Response::_334_Authenticate => "334 ".to_string(),
+            Response::_334_Authenticate(challenge) => format!("334 {}", challenge),
             Response::_354_StartMailInput => "354 End data with <CR><LF>.<CR><LF>".to_string(),
-            Response::_421_ServiceNotAvailable(_domain) => "421".to_string(),
+            Response::_421_ServiceNotAvailable(domain) => {
+                format!("421 {} Service not available, closing transmission channel", domain)
+            }
             Response::_450_MailboxUnavailable => "450".to_string(),
-            Response::_451_ErrorInProcessing => "451".to_string(),
-            Response::_452_InsufficientStorage => "452".to_string(),
+            Response::_451_ErrorInProcessing => {
+                "451 Requested action aborted: local error in processing".to_string()
+            }
+            Response::_452_InsufficientStorage => {
+                "452 Requested action not taken: insufficient system storage".to_string()
+            }
             Response::_455_ServerUnableToAccommodate => "455".to_string(),
-            Response::_500_SyntaxError => "500".to_string(),
-            Response::_501_SyntaxErrorInParameters => "501".to_string(),
-            Response::_502_CommandNotImplemented => "502".to_string(),
-            Response::_503_BadSequence => "503".to_string(),
-            Response::_504_ParameterNotImplemented => "504".to_string(),
+            Response::_500_SyntaxError => "500 Syntax error, command unrecognized".to_string(),
+            Response::_500_LineTooLong => "500 Line too long".to_string(),
+            Response::_501_SyntaxErrorInParameters => {
+                "501 Syntax error in parameters or arguments".to_string()
+            }
+            Response::_502_CommandNotImplemented => "502 Command not implemented".to_string(),
+            Response::_503_BadSequence => "503 Bad sequence of commands".to_string(),
+            Response::_504_ParameterNotImplemented => {
+                "504 Command parameter not implemented".to_string()
+            }
+            Response::_530_AuthenticationRequired => "530 Authentication required".to_string(),
             Response::_535_FailedAuthentication => "535 Failed Authentication".to_string(),
+            Response::_538_EncryptionRequired => {
+                "538 Encryption required for requested authentication mechanism".to_string()
+            }
             Response::_550_MailboxUnavailable => "550".to_string(),
             Response::_551_UserNotLocal => "551".to_string(),
-            Response::_552_ExceededStorageAllocation => "552".to_string(),
-            Response::_553_MailboxNameNotAllowed => "553".to_string(),
-            Response::_554_TransactionFailed => "554".to_string(),
+            Response::_552_ExceededStorageAllocation => {
+                "552 Requested mail action aborted: exceeded storage allocation".to_string()
+            }
+            Response::_552_MessageSizeExceeded => {
+                "552 Message size exceeds fixed maximum message size".to_string()
+            }
+            Response::_553_MailboxNameNotAllowed => {
+                "553 Requested action not taken: mailbox name not allowed".to_string()
+            }
+            Response::_554_TransactionFailed(reason) => format!("554 {}", reason),
             Response::_555_ParametersNotRecognized => "555".to_string(),
         }
     }
@@ -3,24 +3,237 @@ use toml::de;
 use serde_derive::Deserialize;
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
 
 
-#[derive(Deserialize, PartialEq, Eq)]
-pub enum Protocol {
-    V4,
-    V6,
+/// What connections to an address are used for.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Mail relayed from other servers, STARTTLS is offered if TLS is enabled.
+    #[default]
+    Plain,
+    /// Mail submitted by our users, RFC 6409. They must authenticate before sending.
+    Submission,
+    /// Connections encrypted from the start, RFC 8314.
+    Tls,
+}
+
+/// An address to listen on, configured either as just the address,
+/// `"0.0.0.0:25"`, or as a table giving its role, `{ address = "[::]:465", role = "tls" }`.
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(from = "ListenConfig")]
+pub struct Listen {
+    pub address: SocketAddr,
+    pub role: Role,
+    /// Failing to listen on the address is only a warning. This is the case for the
+    /// default IPv6 address, as not every host has IPv6.
+    pub optional: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListenConfig {
+    Address(SocketAddr),
+    Table {
+        address: SocketAddr,
+        #[serde(default)]
+        role: Role,
+    },
+}
+
+impl From<SocketAddr> for Listen {
+    fn from(address: SocketAddr) -> Self {
+        Listen {
+            address,
+            role: Role::Plain,
+            optional: false,
+        }
+    }
+}
+
+impl From<ListenConfig> for Listen {
+    fn from(config: ListenConfig) -> Self {
+        match config {
+            ListenConfig::Address(address) => address.into(),
+            ListenConfig::Table { address, role } => Listen {
+                address,
+                role,
+                optional: false,
+            },
+        }
+    }
+}
+
+/// A user that can authenticate with us, configured as a `[[users]]` table.
+#[derive(Deserialize, Clone)]
+pub struct User {
+    pub username: String,
+    /// Either plaintext, or an argon2 or bcrypt PHC string.
+    /// CRAM-MD5 can only be used by users with a plaintext password.
+    pub password: String,
+    /// The addresses the user may send from, a whole domain can be given as "@domain".
+    /// If empty they can send from any address.
+    #[serde(default)]
+    pub allowed_senders: Vec<String>,
+}
+
+impl User {
+    pub fn new(username: &str, password: &str) -> Self {
+        User {
+            username: username.to_string(),
+            password: password.to_string(),
+            allowed_senders: Vec::new(),
+        }
+    }
+
+    /// Is the user allowed to send mail from the given address?
+    pub fn may_send_from(&self, address: &str) -> bool {
+        self.allowed_senders.is_empty()
+            || self.allowed_senders.iter().any(|allowed| {
+                if allowed.starts_with('@') {
+                    address.to_lowercase().ends_with(&allowed.to_lowercase())
+                } else {
+                    address.eq_ignore_ascii_case(allowed)
+                }
+            })
+    }
+}
+
+/// How received mail is stored.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Each message in its own .eml file.
+    Eml,
+    /// A Maildir that mail clients can read directly.
+    #[default]
+    Maildir,
+    /// Appended to mboxrd files.
+    Mbox,
+    /// A SQLite database, indexed so it can be queried.
+    Sqlite,
+}
+
+/// Where the envelope of a message is kept.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Envelope {
+    /// In a block of X-Envelope headers at the top of the message.
+    #[default]
+    Header,
+    /// In a JSON file alongside the message, the mbox format always uses the headers.
+    Sidecar,
+}
+
+/// Where received mail is kept, configured in the `[storage]` table.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Storage {
+    /// The directory messages are written to, it is created if it doesn't exist.
+    pub path: PathBuf,
+    pub format: Format,
+    /// With the mbox format, append every message to a single file rather than a file
+    /// per recipient.
+    pub single_file: bool,
+    pub envelope: Envelope,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage {
+            path: PathBuf::from("./received"),
+            format: Format::default(),
+            single_file: false,
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+/// Somewhere a received message is handed on to, configured as a `[[sinks]]` table
+/// with its `type`.
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Sink {
+    /// Kept in local storage, configured as for the `[storage]` table.
+    Storage(Storage),
+    /// POSTed to an HTTP URL, the envelope is given in the X-Mail-From and X-Rcpt-To headers.
+    Webhook { url: String },
+    /// Relayed on to another SMTP server at the address, "host:port".
+    Relay { address: String },
+}
+
+/// How a message is handed on when there are several sinks.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkMode {
+    /// Every sink must accept the message.
+    #[default]
+    Fanout,
+    /// Each sink is tried in turn until one accepts the message.
+    Chain,
 }
 
 #[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Settings {
-    pub port: u16,
-    pub protocol: u8,
+    /// The addresses to listen on, IPv6 addresses only accept IPv6 connections
+    /// so both "0.0.0.0" and "[::]" can be given for the same port.
+    pub listen: Vec<Listen>,
     pub domain: String,
-    pub password: String,
+    /// The default user is only there when no settings file is given,
+    /// if the file has no `[[users]]` no one can authenticate.
+    #[serde(default)]
+    pub users: Vec<User>,
+    /// The number of erroneous commands a client may send before we hang up on them.
+    /// Zero allows any number of errors.
+    pub max_errors: usize,
+    /// The largest message in bytes we will accept, advertised with the SIZE extension.
+    /// Zero places no limit on the size.
+    pub max_message_size: usize,
+    /// The longest line in bytes, including the line ending, we will accept in a command
+    /// or the message data, RFC 5321 4.5.3.1. Zero places no limit on the length.
+    pub max_line_length: usize,
+    /// Clients must authenticate before they are allowed to send mail.
+    pub require_auth: bool,
+    /// Only offer and allow AUTH once the connection is encrypted.
+    pub require_tls_for_auth: bool,
+    /// Enable TLS, with a self-signed certificate if `tls_cert` and `tls_key` aren't given.
+    /// Giving the certificate and key enables TLS regardless.
+    pub tls: bool,
+    /// The PEM encoded certificate chain used for TLS.
+    pub tls_cert: Option<PathBuf>,
+    /// The PEM encoded private key for the certificate.
+    pub tls_key: Option<PathBuf>,
+    /// Where the self-signed certificate is kept, clients can pin it from here.
+    pub tls_dir: PathBuf,
+    /// PEM encoded CA certificates used to verify client certificates.
+    /// When given clients are asked for a certificate, and its subject is taken as the
+    /// identity they are authenticated with.
+    pub tls_client_ca: Option<PathBuf>,
+    /// Refuse the TLS handshake if the client doesn't present a valid certificate.
+    pub tls_client_cert_required: bool,
+    pub storage: Storage,
+    /// Where messages are handed on to, if empty they are kept in the `[storage]`.
+    pub sinks: Vec<Sink>,
+    pub sink_mode: SinkMode,
 }
 
+/// Settings that have been replaced, with how to give them now.
+const LEGACY: &[(&str, &str)] = &[
+    (
+        "password",
+        "give each user a [[users]] table with a username and password",
+    ),
+    ("port", r#"give the addresses to listen on, listen = ["0.0.0.0:2525"]"#),
+    ("protocol", r#"give IPv4 or IPv6 addresses to listen on, listen = ["[::]:2525"]"#),
+    (
+        "tls_port",
+        r#"listen with the tls role, listen = [{ address = "0.0.0.0:465", role = "tls" }]"#,
+    ),
+];
 
 impl Settings {
     
@@ -33,19 +246,178 @@ impl Settings {
         let mut data = String::new();
         file.read_to_string(&mut data)?;
         
-        match de::from_str(&data) {
-            Ok (settings) => Ok(settings),
-            Err (err) => Err(Box::new(err)),
+        Settings::parse(&data)
+    }
+
+    /// Parse the settings from Toml, refusing any we don't know.
+    pub fn parse(data: &str) -> Result<Self, Box<dyn Error>> {
+        let value: toml::Value = de::from_str(data)?;
+        for (key, replacement) in LEGACY {
+            if value.get(key).is_some() {
+                return Err(format!("The {} setting is no longer supported, {}", key, replacement).into());
+            }
         }
+
+        Ok(value.try_into()?)
     }
-    
+
+    /// Find the user with the given username.
+    pub fn user(&self, username: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.username == username)
+    }
+}
+
+impl Default for Settings {
     /// Return a default set of settings for when no input file is given.
-    pub fn default() -> Self {
+    /// Any settings missing from the input file also take these values, apart from the
+    /// users.
+    fn default() -> Self {
         Settings {
-            port: 2525,
-            protocol: 4,
+            listen: vec![
+                "0.0.0.0:2525".parse::<SocketAddr>().unwrap().into(),
+                Listen {
+                    optional: true,
+                    ..Listen::from("[::]:2525".parse::<SocketAddr>().unwrap())
+                },
+            ],
             domain: String::from("groove.com"),
-            password: String::from("password"),
+            users: vec![User::new("user", "password")],
+            max_errors: 10,
+            max_message_size: 10 * 1024 * 1024,
+            max_line_length: 1000,
+            require_auth: false,
+            require_tls_for_auth: false,
+            tls: false,
+            tls_cert: None,
+            tls_key: None,
+            tls_dir: PathBuf::from("./tls"),
+            tls_client_ca: None,
+            tls_client_cert_required: false,
+            storage: Storage::default(),
+            sinks: Vec::new(),
+            sink_mode: SinkMode::default(),
         }
     }
 }
+
+#[test]
+fn test_load_users() {
+    let settings: Settings = de::from_str(
+        r#"
+        [[users]]
+        username = "onk"
+        password = "ponk"
+
+        [[users]]
+        username = "ook"
+        password = "pook"
+        allowed_senders = ["ook@onk.com", "@ponk.com"]
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(2, settings.users.len());
+    assert!(settings.user("onk").unwrap().may_send_from("anyone@anywhere.com"));
+
+    let ook = settings.user("ook").unwrap();
+    assert!(ook.may_send_from("Ook@Onk.com"));
+    assert!(ook.may_send_from("wibble@ponk.com"));
+    assert!(!ook.may_send_from("wibble@onk.com"));
+    assert!(settings.user("nobody").is_none());
+}
+
+#[test]
+fn test_load_listen() {
+    let settings: Settings = de::from_str(
+        r#"
+        listen = [
+            "0.0.0.0:25",
+            { address = "[::]:587", role = "submission" },
+            { address = "127.0.0.1:465", role = "tls" },
+        ]
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        vec![
+            Listen {
+                address: "0.0.0.0:25".parse().unwrap(),
+                role: Role::Plain,
+                optional: false,
+            },
+            Listen {
+                address: "[::]:587".parse().unwrap(),
+                role: Role::Submission,
+                optional: false,
+            },
+            Listen {
+                address: "127.0.0.1:465".parse().unwrap(),
+                role: Role::Tls,
+                optional: false,
+            },
+        ],
+        settings.listen
+    );
+    assert!(de::from_str::<Settings>(r#"listen = ["localhost"]"#).is_err());
+}
+
+#[test]
+fn test_listen_defaults() {
+    let settings = Settings::parse(r#"domain = "onk.com""#).unwrap();
+
+    assert_eq!(
+        vec![
+            Listen {
+                address: "0.0.0.0:2525".parse().unwrap(),
+                role: Role::Plain,
+                optional: false,
+            },
+            Listen {
+                address: "[::]:2525".parse().unwrap(),
+                role: Role::Plain,
+                optional: true,
+            },
+        ],
+        settings.listen
+    );
+
+    for key in &["port = 25", "protocol = 6", "tls_port = 465"] {
+        let err = Settings::parse(key).err().unwrap();
+        assert!(err.to_string().contains("listen"));
+    }
+}
+
+#[test]
+fn test_load_sinks() {
+    let settings: Settings = de::from_str(
+        r#"
+        sink_mode = "chain"
+
+        [[sinks]]
+        type = "relay"
+        address = "mail.onk.com:25"
+
+        [[sinks]]
+        type = "storage"
+        format = "mbox"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(SinkMode::Chain, settings.sink_mode);
+    assert!(matches!(&settings.sinks[0], Sink::Relay { address } if address == "mail.onk.com:25"));
+    assert!(matches!(&settings.sinks[1], Sink::Storage(storage)
+        if storage.format == Format::Mbox && storage.path == Path::new("./received")));
+}
+
+#[test]
+fn test_load_without_users() {
+    let settings = Settings::parse(r#"domain = "onk.com""#).unwrap();
+    assert!(settings.users.is_empty());
+    assert!(settings.user("user").is_none());
+
+    let err = Settings::parse(r#"password = "ponk""#).err().unwrap();
+    assert!(err.to_string().contains("[[users]]"));
+    assert!(Settings::parse(r#"wibble = true"#).is_err());
+}
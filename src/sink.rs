@@ -0,0 +1,511 @@
+use crate::message::Message;
+use crate::settings::{self, Settings, SinkMode};
+use crate::storage::{self, Storage};
+use async_trait::async_trait;
+use futures::future;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Uri};
+use std::{error, fmt, time};
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Why a sink failed to take a message, this decides how we reply to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The client can try sending the message again later.
+    Temporary,
+    /// We have run out of space, the client can try again later.
+    InsufficientStorage,
+    /// The message won't ever be accepted.
+    Permanent,
+}
+
+#[derive(Debug)]
+pub struct SinkError {
+    pub failure: Failure,
+    pub reason: String,
+}
+
+impl SinkError {
+    pub fn new(failure: Failure, reason: impl fmt::Display) -> Self {
+        SinkError {
+            failure,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} failure, {}", self.failure, self.reason)
+    }
+}
+
+impl error::Error for SinkError {}
+
+impl From<io::Error> for SinkError {
+    fn from(err: io::Error) -> Self {
+        let failure = match err.kind() {
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => {
+                Failure::InsufficientStorage
+            }
+            _ => Failure::Temporary,
+        };
+        SinkError::new(failure, err)
+    }
+}
+
+/// Takes the messages once they have been received.
+#[async_trait]
+pub trait MessageSink: Send + Sync {
+    /// Hand the message on, it is only accepted from the client if this succeeds.
+    async fn deliver(&self, message: &Message) -> Result<(), SinkError>;
+}
+
+/// Keep the message in local storage.
+pub struct Store(pub Box<dyn Storage>);
+
+#[async_trait]
+impl MessageSink for Store {
+    async fn deliver(&self, message: &Message) -> Result<(), SinkError> {
+        Ok(self.0.store(message).await?)
+    }
+}
+
+/// POST the message to a URL, with the envelope in the headers.
+/// A 4xx status other than 408 or 429 means the message will never be accepted.
+pub struct Webhook {
+    client: Client<HttpConnector>,
+    url: Uri,
+    timeout: time::Duration,
+}
+
+/// How long we wait for the webhook to reply, well within the 10 minutes the client waits
+/// for its reply to the final ".", RFC 5321 4.5.3.2.6.
+const WEBHOOK_TIMEOUT: time::Duration = time::Duration::from_secs(300);
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Self, Box<dyn error::Error>> {
+        let url: Uri = url.parse()?;
+        if url.scheme_str() != Some("http") {
+            return Err(format!("Only http webhooks are supported, not {}", url).into());
+        }
+
+        Ok(Webhook {
+            client: Client::new(),
+            url,
+            timeout: WEBHOOK_TIMEOUT,
+        })
+    }
+}
+
+#[async_trait]
+impl MessageSink for Webhook {
+    async fn deliver(&self, message: &Message) -> Result<(), SinkError> {
+        let mut request = Request::post(&self.url)
+            .header("Content-Type", "message/rfc822")
+            .header("X-Mail-From", message.from.as_deref().unwrap_or_default());
+        for to in &message.to {
+            request = request.header("X-Rcpt-To", to.as_str());
+        }
+        let request = request
+            .body(Body::from(message.get_data()))
+            .map_err(|err| SinkError::new(Failure::Permanent, err))?;
+
+        let response = tokio::time::timeout(self.timeout, self.client.request(request))
+            .await
+            .map_err(|_| SinkError::new(Failure::Temporary, "Webhook timed out"))?
+            .map_err(|err| SinkError::new(Failure::Temporary, err))?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_client_error() && status != 408 && status != 429 {
+            Err(SinkError::new(Failure::Permanent, status))
+        } else {
+            Err(SinkError::new(Failure::Temporary, status))
+        }
+    }
+}
+
+/// Relay the message on to another SMTP server.
+pub struct Relay {
+    address: String,
+    domain: String,
+}
+
+/// How long we wait for the server we are relaying to, RFC 5321 4.5.3.2.
+const RELAY_TIMEOUT: time::Duration = time::Duration::from_secs(300);
+
+/// The MAIL and RCPT parameters we pass on, with the extension the server must offer
+/// for us to do so.
+const RELAY_PARAMETERS: &[(&str, &str)] = &[
+    ("BODY", "8BITMIME"),
+    ("SMTPUTF8", "SMTPUTF8"),
+    ("RET", "DSN"),
+    ("ENVID", "DSN"),
+    ("NOTIFY", "DSN"),
+    ("ORCPT", "DSN"),
+];
+
+impl Relay {
+    pub fn new(address: &str, domain: &str) -> Self {
+        Relay {
+            address: address.to_string(),
+            domain: domain.to_string(),
+        }
+    }
+
+    /// Read the server's reply, failing unless its code is one we expected.
+    async fn expect(
+        stream: &mut BufReader<TcpStream>,
+        expected: &[u16],
+    ) -> Result<String, SinkError> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            let read = tokio::time::timeout(RELAY_TIMEOUT, stream.read_line(&mut line))
+                .await
+                .map_err(|_| SinkError::new(Failure::Temporary, "Relay timed out"))??;
+            if read == 0 {
+                return Err(SinkError::new(
+                    Failure::Temporary,
+                    "Relay closed the connection",
+                ));
+            }
+            reply.push_str(&line);
+            // The last line of the reply has a space following the code.
+            if line.chars().nth(3) != Some('-') {
+                break;
+            }
+        }
+
+        let reply = reply.trim_end();
+        match reply.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+            Some(code) if expected.contains(&code) => Ok(reply.to_string()),
+            Some(452) => Err(SinkError::new(Failure::InsufficientStorage, reply)),
+            Some(code) if code >= 500 => Err(SinkError::new(Failure::Permanent, reply)),
+            _ => Err(SinkError::new(Failure::Temporary, reply)),
+        }
+    }
+
+    /// Send a command to the server and read its reply.
+    async fn command(
+        stream: &mut BufReader<TcpStream>,
+        command: &str,
+        expected: &[u16],
+    ) -> Result<String, SinkError> {
+        stream
+            .get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        Relay::expect(stream, expected).await
+    }
+
+    /// The extensions the server offered in its EHLO reply, the greeting line is skipped.
+    fn extensions(reply: &str) -> Vec<String> {
+        reply
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.get(4..)?.split_whitespace().next())
+            .map(|extension| extension.to_uppercase())
+            .collect()
+    }
+
+    /// The command with the parameters the server can take following it.
+    fn with_parameters(command: String, parameters: &[String], extensions: &[String]) -> String {
+        parameters
+            .iter()
+            .filter(|parameter| {
+                let keyword = parameter.split('=').next().unwrap_or_default();
+                RELAY_PARAMETERS.iter().any(|(name, extension)| {
+                    keyword.eq_ignore_ascii_case(name) && extensions.iter().any(|e| e == extension)
+                })
+            })
+            .fold(command, |command, parameter| format!("{} {}", command, parameter))
+    }
+}
+
+#[async_trait]
+impl MessageSink for Relay {
+    async fn deliver(&self, message: &Message) -> Result<(), SinkError> {
+        let stream = tokio::time::timeout(RELAY_TIMEOUT, TcpStream::connect(&self.address))
+            .await
+            .map_err(|_| SinkError::new(Failure::Temporary, "Relay timed out"))??;
+        let mut stream = BufReader::new(stream);
+
+        Relay::expect(&mut stream, &[220]).await?;
+        let reply = Relay::command(&mut stream, &format!("EHLO {}", self.domain), &[250]).await?;
+        let extensions = Relay::extensions(&reply);
+
+        let from = message.from.as_deref().unwrap_or_default();
+        let mail = Relay::with_parameters(
+            format!("MAIL FROM:<{}>", from),
+            &message.from_parameters,
+            &extensions,
+        );
+        Relay::command(&mut stream, &mail, &[250]).await?;
+        for (idx, to) in message.to.iter().enumerate() {
+            let parameters = message.to_parameters.get(idx).map_or(&[][..], |p| p.as_slice());
+            let rcpt = Relay::with_parameters(format!("RCPT TO:<{}>", to), parameters, &extensions);
+            Relay::command(&mut stream, &rcpt, &[250, 251]).await?;
+        }
+        Relay::command(&mut stream, "DATA", &[354]).await?;
+
+        // We are passing the message on, so only add our Received header, RFC 5321 4.4.
+        // Any line starting with a "." has another added, RFC 5321 4.5.2.
+        let mut data = Vec::new();
+        for line in message
+            .with_headers(&message.received_header())
+            .split_inclusive(|b| *b == b'\n')
+        {
+            if line.starts_with(b".") {
+                data.push(b'.');
+            }
+            data.extend_from_slice(line);
+        }
+        data.extend_from_slice(b".\r\n");
+        stream.get_mut().write_all(&data).await?;
+        Relay::expect(&mut stream, &[250]).await?;
+
+        // The message has been accepted, so it doesn't matter how quitting goes.
+        let _ = Relay::command(&mut stream, "QUIT", &[221]).await;
+        Ok(())
+    }
+}
+
+/// Every sink must take the message, they are all given it at once.
+pub struct Fanout(pub Vec<Box<dyn MessageSink>>);
+
+#[async_trait]
+impl MessageSink for Fanout {
+    async fn deliver(&self, message: &Message) -> Result<(), SinkError> {
+        let results = future::join_all(self.0.iter().map(|sink| sink.deliver(message))).await;
+        results.into_iter().collect()
+    }
+}
+
+/// The sinks are tried in turn until one takes the message.
+pub struct Chain(pub Vec<Box<dyn MessageSink>>);
+
+#[async_trait]
+impl MessageSink for Chain {
+    async fn deliver(&self, message: &Message) -> Result<(), SinkError> {
+        let mut result = Ok(());
+        for sink in &self.0 {
+            if let Err(ref err) = result {
+                eprintln!("Sink failed: {}, trying the next", err);
+            }
+            result = sink.deliver(message).await;
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+}
+
+/// Set up the sink given in the settings.
+fn open_sink(
+    sink: &settings::Sink,
+    settings: &Settings,
+) -> Result<Box<dyn MessageSink>, Box<dyn error::Error>> {
+    Ok(match sink {
+        settings::Sink::Storage(storage) => {
+            Box::new(Store(storage::open(storage, &settings.domain)?))
+        }
+        settings::Sink::Webhook { url } => Box::new(Webhook::new(url)?),
+        settings::Sink::Relay { address } => Box::new(Relay::new(address, &settings.domain)),
+    })
+}
+
+/// Set up the sinks given in the settings, falling back to the `[storage]` if none are.
+pub fn open(settings: &Settings) -> Result<Box<dyn MessageSink>, Box<dyn error::Error>> {
+    if settings.sinks.is_empty() {
+        return Ok(Box::new(Store(storage::open(
+            &settings.storage,
+            &settings.domain,
+        )?)));
+    }
+
+    let sinks = settings
+        .sinks
+        .iter()
+        .map(|sink| open_sink(sink, settings))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(match settings.sink_mode {
+        SinkMode::Fanout => Box::new(Fanout(sinks)),
+        SinkMode::Chain => Box::new(Chain(sinks)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::{converse, Tls};
+    use tokio::net::TcpListener;
+    use tokio::stream::StreamExt;
+
+    /// A sink that always gives the same result.
+    struct Fixed(Option<Failure>);
+
+    #[async_trait]
+    impl MessageSink for Fixed {
+        async fn deliver(&self, _message: &Message) -> Result<(), SinkError> {
+            match self.0 {
+                Some(failure) => Err(SinkError::new(failure, "Fixed")),
+                None => Ok(()),
+            }
+        }
+    }
+
+    fn failure(sink: &dyn MessageSink) -> Option<Failure> {
+        tokio_test::block_on(sink.deliver(&Message::new()))
+            .err()
+            .map(|err| err.failure)
+    }
+
+    #[test]
+    fn test_fanout() {
+        let sink = Fanout(vec![Box::new(Fixed(None)), Box::new(Fixed(None))]);
+        assert_eq!(None, failure(&sink));
+
+        let sink = Fanout(vec![
+            Box::new(Fixed(None)),
+            Box::new(Fixed(Some(Failure::Permanent))),
+        ]);
+        assert_eq!(Some(Failure::Permanent), failure(&sink));
+    }
+
+    #[test]
+    fn test_chain() {
+        let sink = Chain(vec![
+            Box::new(Fixed(Some(Failure::Temporary))),
+            Box::new(Fixed(None)),
+        ]);
+        assert_eq!(None, failure(&sink));
+
+        let sink = Chain(vec![
+            Box::new(Fixed(Some(Failure::Temporary))),
+            Box::new(Fixed(Some(Failure::InsufficientStorage))),
+        ]);
+        assert_eq!(Some(Failure::InsufficientStorage), failure(&sink));
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        use hyper::service::{make_service_fn, service_fn};
+        use hyper::{Response, Server, StatusCode};
+        use std::convert::Infallible;
+        use std::sync::{Arc, Mutex};
+
+        // Each request is kept, the path gives the status to reply with or "hang" to never
+        // reply at all.
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let kept = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let kept = kept.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let kept = kept.clone();
+                    async move {
+                        if request.uri().path() == "/hang" {
+                            future::pending::<()>().await;
+                        }
+                        let status: u16 = request.uri().path()[1..].parse().unwrap();
+                        let headers = request.headers().clone();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        kept.lock().unwrap().push((headers, body));
+                        let response = Response::builder()
+                            .status(StatusCode::from_u16(status).unwrap())
+                            .body(Body::empty())
+                            .unwrap();
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let mut message = Message::new();
+        message.from = Some("ook@onk.com".to_string());
+        message.to = vec!["pook@ook.co.uk".to_string(), "onk@ponk.com".to_string()];
+        message.data = b"Subject: Ook\r\n\r\nOnk\r\n".to_vec();
+
+        let webhook =
+            |status: u16| Webhook::new(&format!("http://{}/{}", address, status)).unwrap();
+        webhook(200).deliver(&message).await.unwrap();
+        let failure = |result: Result<(), SinkError>| result.unwrap_err().failure;
+        assert_eq!(Failure::Permanent, failure(webhook(400).deliver(&message).await));
+        assert_eq!(Failure::Temporary, failure(webhook(429).deliver(&message).await));
+        assert_eq!(Failure::Temporary, failure(webhook(503).deliver(&message).await));
+
+        let hung = Webhook {
+            timeout: time::Duration::from_millis(100),
+            ..Webhook::new(&format!("http://{}/hang", address)).unwrap()
+        };
+        assert_eq!(Failure::Temporary, failure(hung.deliver(&message).await));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(4, requests.len());
+        let (headers, body) = &requests[0];
+        assert_eq!("message/rfc822", headers["Content-Type"]);
+        assert_eq!("ook@onk.com", headers["X-Mail-From"]);
+        let to: Vec<_> = headers.get_all("X-Rcpt-To").iter().collect();
+        assert_eq!(vec!["pook@ook.co.uk", "onk@ponk.com"], to);
+        assert_eq!(message.get_data(), body.to_vec());
+
+        assert!(Webhook::new("https://onk.com/").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = Relay::new(&listener.local_addr().unwrap().to_string(), "groove.com");
+        let settings = Settings::default();
+
+        let server = async {
+            let stream = listener.next().await.unwrap().unwrap();
+            let mut messages = Vec::new();
+            converse(stream, None, &settings, Tls::Disabled, |message| {
+                messages.push(message);
+                future::ready(Ok(()))
+            })
+            .await
+            .unwrap();
+            messages
+        };
+
+        let mut message = Message::new();
+        message.from = Some("ook@onk.com".to_string());
+        message.to = vec!["pook@ook.co.uk".to_string(), "onk@ponk.com".to_string()];
+        // We don't offer DSN, so only the parameters for the extensions we do are passed on.
+        message.from_parameters = vec![
+            "BODY=8BITMIME".to_string(),
+            "SMTPUTF8".to_string(),
+            "RET=HDRS".to_string(),
+        ];
+        message.to_parameters = vec![vec!["NOTIFY=NEVER".to_string()], Vec::new()];
+        message.data = b"Subject: Ook\r\n\r\n.Onk\r\n..\r\n".to_vec();
+
+        let (messages, result) = future::join(server, relay.deliver(&message)).await;
+        result.unwrap();
+
+        assert_eq!(1, messages.len());
+        assert_eq!(message.from, messages[0].from);
+        assert_eq!(message.to, messages[0].to);
+        assert_eq!(
+            vec!["BODY=8BITMIME".to_string(), "SMTPUTF8".to_string()],
+            messages[0].from_parameters
+        );
+        assert_eq!(vec![Vec::<String>::new(); 2], messages[0].to_parameters);
+        assert_eq!(
+            message.with_headers(&message.received_header()),
+            messages[0].data
+        );
+        assert_eq!("ESMTP", messages[0].protocol);
+    }
+}
@@ -1,13 +1,26 @@
-use crate::commands::Command;
-use crate::message::Message;
+use crate::auth;
+use crate::codec::{self, Line, SmtpCodec};
+use crate::commands::{Command, ParseError};
+use crate::message::{Message, TlsInfo};
 use crate::responses::Response;
 use crate::settings::Settings;
-use base64;
-use futures::sink::*;
-use std::{error, fmt};
+use crate::sink::{Failure, SinkError};
+use crate::tls;
+use chrono::Utc;
+use futures::sink::SinkExt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::{error, fmt, mem};
 use tokio::prelude::*;
 use tokio::stream::StreamExt;
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio_rustls::rustls::Session;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::Framed;
+
+/// A stream we can hold a conversation over, either plain or encrypted.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
 
 #[derive (Debug)]
 pub struct ConnectionError;
@@ -21,12 +34,6 @@ impl fmt::Display for ConnectionError {
 impl error::Error for ConnectionError {}
     
 
-#[derive(Clone, Copy)]
-pub enum Authentication {
-    ReceiveAuthCommand,
-    ReceivePlainAuth,
-}
-
 #[derive(Clone, Copy, Debug)]
 pub enum State {
     SendGreeting,
@@ -37,65 +44,441 @@ pub enum State {
     End,
 }
 
+/// How an authentication exchange came to an end.
+enum AuthOutcome {
+    /// The client is now authenticated with the given identity.
+    Authenticated(String),
+    /// The client's credentials were not valid.
+    Failed,
+    /// The client cancelled the exchange, or sent something we couldn't decode.
+    Aborted,
+}
+
+/// How TLS is offered on a connection.
+#[derive(Clone, Copy)]
+pub enum Tls<'a> {
+    /// TLS isn't available.
+    Disabled,
+    /// The client may upgrade the connection with the STARTTLS command, RFC 3207.
+    Upgrade(&'a TlsAcceptor),
+    /// The connection is encrypted as soon as it is made, RFC 8314.
+    Implicit(&'a TlsAcceptor),
+}
+
+/// The phases of a mail transaction, RFC 5321 section 3.3.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transaction {
+    /// No transaction is in progress, we are waiting for a MAIL command.
+    Idle,
+    /// MAIL has been received, we are waiting for the first RCPT command.
+    Sender,
+    /// At least one RCPT has been received, more RCPTs or DATA may follow.
+    Recipients,
+}
+
 async fn respond<'a, T>(
-    stream: &mut Framed<T, LinesCodec>,
+    stream: &mut Framed<T, SmtpCodec>,
     response: Response<'a>,
 ) -> Result<(), Box<dyn error::Error>>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    stream.send(response.as_string()).await?;
+    for line in response.as_lines() {
+        stream.feed(line).await?;
+    }
+    SinkExt::<String>::flush(stream).await?;
     Ok(())
 }
 
-async fn authentication<T>(
-    mut stream: &mut Framed<T, LinesCodec>,
+/// Parse a line from the client as a command, it must be valid UTF-8.
+fn parse_command(line: Line) -> Result<Command, ParseError> {
+    match line {
+        Line::Text(line) => std::str::from_utf8(codec::trim_ending(&line))
+            .map_err(|_| ParseError::Unrecognized)?
+            .parse(),
+        Line::TooLong => Err(ParseError::TooLong),
+    }
+}
+
+/// The service extensions we advertise in response to EHLO.
+fn extensions(settings: &Settings, starttls: bool, mechanisms: &[&str]) -> Vec<String> {
+    let mut extensions = vec![
+        "PIPELINING".to_string(),
+        "8BITMIME".to_string(),
+        "SMTPUTF8".to_string(),
+    ];
+
+    if starttls {
+        extensions.push("STARTTLS".to_string());
+    }
+
+    if settings.max_message_size > 0 {
+        extensions.push(format!("SIZE {}", settings.max_message_size));
+    } else {
+        extensions.push("SIZE".to_string());
+    }
+
+    if !mechanisms.is_empty() {
+        extensions.push(format!("AUTH {}", mechanisms.join(" ")));
+    }
+    extensions
+}
+
+/// The size of the message the client declared with the SIZE parameter to MAIL, RFC 1870.
+fn declared_size(parameters: &[String]) -> Option<usize> {
+    parameters.iter().find_map(|parameter| {
+        let (keyword, value) = parameter.split_once('=')?;
+        if keyword.eq_ignore_ascii_case("SIZE") {
+            value.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// The SASL mechanisms the client can authenticate with.
+/// EXTERNAL is only available if they presented a certificate, and CRAM-MD5 if a user
+/// has a plaintext password.
+fn mechanisms(settings: &Settings, encrypted: bool, certificate: bool) -> Vec<&'static str> {
+    let mut mechanisms = Vec::new();
+    if encrypted || !settings.require_tls_for_auth {
+        mechanisms.extend(auth::MECHANISMS.iter().filter(|mechanism| {
+            **mechanism != "CRAM-MD5" || auth::cram_md5_available(settings)
+        }));
+        if certificate {
+            mechanisms.push("EXTERNAL");
+        }
+    }
+    mechanisms
+}
+
+/// The protocol a message was received with, for the Received header, RFC 3848.
+fn protocol(extended: bool, encrypted: bool, authenticated: bool) -> String {
+    if !extended {
+        return "SMTP".to_string();
+    }
+
+    let mut protocol = "ESMTP".to_string();
+    if encrypted {
+        protocol.push('S');
+    }
+    if authenticated {
+        protocol.push('A');
+    }
+    protocol
+}
+
+/// The reply for a message we were unable to deliver.
+fn delivery_error_response(err: &SinkError) -> Response<'static> {
+    match err.failure {
+        Failure::Temporary => Response::_451_ErrorInProcessing,
+        Failure::InsufficientStorage => Response::_452_InsufficientStorage,
+        Failure::Permanent => Response::_554_TransactionFailed("Message rejected"),
+    }
+}
+
+/// Perform the TLS handshake, returning the encrypted stream along with what was
+/// negotiated, including the identity given by the client's certificate if they presented one.
+async fn encrypt<T>(
+    acceptor: &TlsAcceptor,
+    stream: T,
+    settings: &Settings,
+) -> Result<(Framed<Box<dyn Io>, SmtpCodec>, TlsInfo), Box<dyn error::Error>>
+where
+    T: Io + 'static,
+{
+    let stream = acceptor.accept(stream).await?;
+    let (_, session) = stream.get_ref();
+    let info = TlsInfo {
+        version: session
+            .get_protocol_version()
+            .map_or_else(String::new, |version| format!("{:?}", version)),
+        cipher: session
+            .get_negotiated_ciphersuite()
+            .map_or_else(String::new, |cipher| format!("{:?}", cipher.suite)),
+        client_certificate: session
+            .get_peer_certificates()
+            .and_then(|certs| tls::certificate_identity(&certs)),
+    };
+
+    let codec = SmtpCodec::new(settings.max_line_length);
+    Ok((Framed::new(Box::new(stream), codec), info))
+}
+
+/// Greet the client in reply to their HELO, or their EHLO in which case we also tell
+/// them the extensions we support.
+async fn greet<T>(
+    stream: &mut Framed<T, SmtpCodec>,
     settings: &Settings,
-) -> Result<bool, Box<dyn error::Error>>
+    extensions: Option<&[String]>,
+) -> Result<(), Box<dyn error::Error>>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    let mut stage = Authentication::ReceiveAuthCommand;
+    let greeting = format!("{}, I hope this day finds you well.", settings.domain);
+    match extensions {
+        Some(extensions) => respond(stream, Response::_250_Extensions(&greeting, extensions)).await,
+        None => respond(stream, Response::_250_Completed(&greeting)).await,
+    }
+}
 
-    loop {
-        match stream.next().await {
-            Some(line) => {
-                match stage {
-                    Authentication::ReceiveAuthCommand => match Command::from_str(&line?) {
-                        Some(Command::AUTH(_)) => {
-                            respond(&mut stream, Response::_334_Authenticate).await?;
-                            stage = Authentication::ReceivePlainAuth;
-                        }
-                        _ => {
-                            respond(&mut stream, Response::_503_BadSequence).await?;
-                            stage = Authentication::ReceiveAuthCommand;
-                        }
-                    },
-                    
-                    Authentication::ReceivePlainAuth => {
-                        if base64::encode(&settings.password) == line? {
-                            respond(&mut stream, Response::_235_AuthenticationSuccessful).await?;
-                            return Ok(true);
-                        } else {
-                            respond(&mut stream, Response::_535_FailedAuthentication).await?;
-                            stage = Authentication::ReceiveAuthCommand;
-                        }
-                    }
+/// The response to give to a line that couldn't be parsed as a command.
+fn parse_error_response(error: ParseError) -> Response<'static> {
+    match error {
+        ParseError::Unrecognized => Response::_500_SyntaxError,
+        ParseError::NotImplemented => Response::_502_CommandNotImplemented,
+        ParseError::InvalidParameters => Response::_501_SyntaxErrorInParameters,
+        ParseError::TooLong => Response::_500_LineTooLong,
+    }
+}
+
+/// Send an authentication challenge to the client and wait for their response.
+/// Returns None if the client cancels the exchange.
+async fn challenge<T>(
+    stream: &mut Framed<T, SmtpCodec>,
+    challenge: &str,
+) -> Result<Option<String>, Box<dyn error::Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    respond(stream, Response::_334_Authenticate(challenge)).await?;
+    match stream.next().await {
+        Some(line) => match line? {
+            Line::Text(line) => {
+                let line = String::from_utf8_lossy(codec::trim_ending(&line)).into_owned();
+                if line == "*" {
+                    Ok(None)
+                } else {
+                    Ok(Some(line))
                 }
             }
-            None => return Err(Box::new(ConnectionError))
+            // Far too long to be a valid response, so give up on the exchange.
+            Line::TooLong => Ok(None),
+        },
+        None => Err(Box::new(ConnectionError)),
+    }
+}
+
+/// Use the initial response sent with the AUTH command, or challenge the client for it
+/// if there wasn't one.
+async fn initial_response<T>(
+    stream: &mut Framed<T, SmtpCodec>,
+    initial_response: Option<String>,
+    prompt: &str,
+) -> Result<Option<String>, Box<dyn error::Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    match initial_response {
+        // A lone "=" is an initial response that is empty.
+        Some(response) if response == "=" => Ok(Some(String::new())),
+        Some(response) if response == "*" => Ok(None),
+        Some(response) => Ok(Some(response)),
+        None => challenge(stream, prompt).await,
+    }
+}
+
+/// The PLAIN mechanism, RFC 4616.
+async fn authenticate_plain<T>(
+    stream: &mut Framed<T, SmtpCodec>,
+    settings: &Settings,
+    response: Option<String>,
+) -> Result<AuthOutcome, Box<dyn error::Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let plain = initial_response(stream, response, "")
+        .await?
+        .and_then(|response| auth::decode_plain(&response));
+
+    Ok(match plain {
+        Some(plain) => match auth::verify_plain(settings, &plain) {
+            Some(identity) => AuthOutcome::Authenticated(identity),
+            None => AuthOutcome::Failed,
+        },
+        None => AuthOutcome::Aborted,
+    })
+}
+
+/// The LOGIN mechanism, the username and password are prompted for separately.
+async fn authenticate_login<T>(
+    stream: &mut Framed<T, SmtpCodec>,
+    settings: &Settings,
+    response: Option<String>,
+) -> Result<AuthOutcome, Box<dyn error::Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let username = initial_response(stream, response, auth::LOGIN_USERNAME)
+        .await?
+        .and_then(|response| auth::decode(&response));
+    let username = match username {
+        Some(username) => username,
+        None => return Ok(AuthOutcome::Aborted),
+    };
+
+    let password = challenge(stream, auth::LOGIN_PASSWORD)
+        .await?
+        .and_then(|response| auth::decode(&response));
+    let password = match password {
+        Some(password) => password,
+        None => return Ok(AuthOutcome::Aborted),
+    };
+
+    if auth::verify(settings, &username, &password) {
+        Ok(AuthOutcome::Authenticated(username))
+    } else {
+        Ok(AuthOutcome::Failed)
+    }
+}
+
+/// The CRAM-MD5 mechanism, RFC 2195.
+/// The client proves they know the password without sending it.
+async fn authenticate_cram_md5<T>(
+    stream: &mut Framed<T, SmtpCodec>,
+    settings: &Settings,
+    response: Option<String>,
+) -> Result<AuthOutcome, Box<dyn error::Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // The server speaks first with this mechanism, so there can be no initial response.
+    if response.is_some() {
+        return Ok(AuthOutcome::Aborted);
+    }
+
+    let cram_challenge = auth::cram_md5_challenge(&settings.domain);
+    let response = challenge(stream, &base64::encode(&cram_challenge))
+        .await?
+        .and_then(|response| auth::decode(&response));
+
+    Ok(match response {
+        Some(response) => match auth::verify_cram_md5(settings, &cram_challenge, &response) {
+            Some(identity) => AuthOutcome::Authenticated(identity),
+            None => AuthOutcome::Failed,
+        },
+        None => AuthOutcome::Aborted,
+    })
+}
+
+/// The EXTERNAL mechanism, RFC 4422 appendix A.
+/// The client is authenticated with the identity from their TLS certificate.
+async fn authenticate_external<T>(
+    stream: &mut Framed<T, SmtpCodec>,
+    certificate: &str,
+    response: Option<String>,
+) -> Result<AuthOutcome, Box<dyn error::Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // The client may only ask to act as the identity in their certificate.
+    let authzid = initial_response(stream, response, "").await?.and_then(|response| {
+        if response.is_empty() {
+            Some(response)
+        } else {
+            auth::decode(&response)
+        }
+    });
+
+    Ok(match authzid {
+        Some(authzid) if authzid.is_empty() || authzid == certificate => {
+            AuthOutcome::Authenticated(certificate.to_string())
+        }
+        Some(_) => AuthOutcome::Failed,
+        None => AuthOutcome::Aborted,
+    })
+}
+
+/// Authenticate the client using the mechanism given in their AUTH command.
+/// Returns the identity they are now authenticated as, if any.
+async fn authentication<T>(
+    stream: &mut Framed<T, SmtpCodec>,
+    settings: &Settings,
+    mechanism: &str,
+    initial_response: Option<String>,
+    certificate: Option<&str>,
+) -> Result<Option<String>, Box<dyn error::Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let outcome = match (mechanism, certificate) {
+        ("PLAIN", _) => authenticate_plain(stream, settings, initial_response).await?,
+        ("LOGIN", _) => authenticate_login(stream, settings, initial_response).await?,
+        ("CRAM-MD5", _) if auth::cram_md5_available(settings) => {
+            authenticate_cram_md5(stream, settings, initial_response).await?
+        }
+        ("EXTERNAL", Some(certificate)) => {
+            authenticate_external(stream, certificate, initial_response).await?
+        }
+        _ => {
+            respond(stream, Response::_504_ParameterNotImplemented).await?;
+            return Ok(None);
+        }
+    };
+
+    match outcome {
+        AuthOutcome::Authenticated(identity) => {
+            respond(stream, Response::_235_AuthenticationSuccessful).await?;
+            Ok(Some(identity))
+        }
+        AuthOutcome::Failed => {
+            respond(stream, Response::_535_FailedAuthentication).await?;
+            Ok(None)
+        }
+        AuthOutcome::Aborted => {
+            respond(stream, Response::_501_SyntaxErrorInParameters).await?;
+            Ok(None)
         }
     }
 }
 
-pub async fn converse<T: AsyncRead + AsyncWrite + Unpin>(
-    mut stream: Framed<T, LinesCodec>,
+/// Hold the SMTP conversation with a client.
+/// Each time a message transaction completes the message is handed to `deliver`,
+/// so a client can send any number of messages over the one connection.
+pub async fn converse<T, F, Fut>(
+    stream: T,
+    peer: Option<SocketAddr>,
     settings: &Settings,
-) -> Result<Message, Box<dyn error::Error>> {
+    tls: Tls<'_>,
+    mut deliver: F,
+) -> Result<(), Box<dyn error::Error>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: FnMut(Message) -> Fut,
+    Fut: Future<Output = Result<(), SinkError>>,
+{
+    // The stream is boxed so it can be swapped for an encrypted one part way through.
+    let mut encrypted = false;
+    // The identity from the client's TLS certificate, this lets them send mail in place of
+    // authenticating, or authenticate with the EXTERNAL mechanism.
+    let mut certificate = None;
+    let mut tls_info = None;
+    let mut stream: Framed<Box<dyn Io>, SmtpCodec> = match tls {
+        Tls::Implicit(acceptor) => {
+            let (encrypted_stream, info) = encrypt(acceptor, stream, settings).await?;
+            encrypted = true;
+            certificate = info.client_certificate.clone();
+            tls_info = Some(info);
+            encrypted_stream
+        }
+        _ => Framed::new(Box::new(stream), SmtpCodec::new(settings.max_line_length)),
+    };
     let mut message = Message::new();
     let mut state = State::SendGreeting;
+    let mut transaction = Transaction::Idle;
+    let mut errors = 0;
+    let mut oversized = false;
+    let mut extended = false;
+    let mut authenticated: Option<String> = None;
+    let mut helo = None;
 
     loop {
+        let awaiting_command = matches!(state, State::ReceiveGreeting | State::Accept);
+        if awaiting_command && settings.max_errors > 0 && errors >= settings.max_errors {
+            // The client is clearly confused, so hang up on them.
+            state = State::Rejected;
+        }
+
         match state {
             State::SendGreeting => {
                 // Send the initial greeting.
@@ -108,43 +491,37 @@ pub async fn converse<T: AsyncRead + AsyncWrite + Unpin>(
                     Some(line) => {
                         // The first command we must recieve must be an EHLO or a HELO command.
                         // Then if it is correct we can get on with the main command loop.
-                        match Command::from_str(&line?) {
-                            Some(Command::HELO(_)) => {
-                                respond(
-                                    &mut stream,
-                                    Response::_250_Completed(&format!(
-                                        "{}, I hope this day finds you well.",
-                                        settings.domain
-                                    )),
-                                )
-                                    .await?;
+                        match parse_command(line?) {
+                            Ok(Command::HELO(name)) => {
+                                helo = Some(name);
+                                extended = false;
+                                greet(&mut stream, settings, None).await?;
                                 state = State::Accept;
                             }
-                            Some(Command::EHLO(_)) => {
-                                respond(
-                                    &mut stream,
-                                    Response::_250_Completed(&format!(
-                                        "{}, I hope this day finds you well.",
-                                        settings.domain
-                                    )),
-                                )
-                                    .await?;
-                                respond(&mut stream, Response::_250_Completed("AUTH PLAIN")).await?;
-
-                                // Authentication must pass before we can get beyond this stage.
-                                if authentication(&mut stream, settings).await? == true {
-                                    state = State::Accept;
-                                } else {
-                                    state = State::End;
-                                }
+                            Ok(Command::EHLO(name)) => {
+                                helo = Some(name);
+                                extended = true;
+                                let starttls = matches!(tls, Tls::Upgrade(_)) && !encrypted;
+                                let mechanisms =
+                                    mechanisms(settings, encrypted, certificate.is_some());
+                                let extensions = extensions(settings, starttls, &mechanisms);
+                                greet(&mut stream, settings, Some(&extensions)).await?;
+                                state = State::Accept;
                             }
-                            Some(_) => {
+                            Ok(Command::RSET) | Ok(Command::NOOP) => {
+                                respond(&mut stream, Response::_250_Completed("OK")).await?;
+                            }
+                            Ok(Command::QUIT) => {
+                                respond(&mut stream, Response::_221_ServiceClosing).await?;
+                                state = State::End;
+                            }
+                            Ok(_) => {
+                                errors += 1;
                                 respond(&mut stream, Response::_503_BadSequence).await?;
-                                state = State::ReceiveGreeting;
                             }
-                            None => {
-                                respond(&mut stream, Response::_502_CommandNotImplemented).await?;
-                                state = State::ReceiveGreeting;
+                            Err(err) => {
+                                errors += 1;
+                                respond(&mut stream, parse_error_response(err)).await?;
                             }
                         }
                     },
@@ -156,30 +533,175 @@ pub async fn converse<T: AsyncRead + AsyncWrite + Unpin>(
                 match stream.next().await {
                     Some (line) => {
                         // The main command loop over which the email contents are sent.
-                        match Command::from_str(&line?) {
-                            Some(Command::MAIL(from)) => {
-                                message.from = Some(from);
-                                respond(&mut stream, Response::_250_Completed("OK")).await?;
+                        match parse_command(line?) {
+                            Ok(Command::HELO(name)) => {
+                                // Greeting us again starts afresh, as RSET does,
+                                // RFC 5321 4.1.4.
+                                message = Message::new();
+                                transaction = Transaction::Idle;
+                                helo = Some(name);
+                                extended = false;
+                                greet(&mut stream, settings, None).await?;
                             }
-                            Some(Command::RCPT(to)) => {
-                                message.to.push(to);
-                                respond(&mut stream, Response::_250_Completed("OK")).await?;
+                            Ok(Command::EHLO(name)) => {
+                                message = Message::new();
+                                transaction = Transaction::Idle;
+                                helo = Some(name);
+                                extended = true;
+                                let starttls = matches!(tls, Tls::Upgrade(_)) && !encrypted;
+                                let mechanisms =
+                                    mechanisms(settings, encrypted, certificate.is_some());
+                                let extensions = extensions(settings, starttls, &mechanisms);
+                                greet(&mut stream, settings, Some(&extensions)).await?;
+                            }
+                            Ok(Command::MAIL(..))
+                                if settings.require_auth
+                                    && authenticated.is_none()
+                                    && certificate.is_none() =>
+                            {
+                                errors += 1;
+                                respond(&mut stream, Response::_530_AuthenticationRequired).await?;
                             }
-                            Some(Command::VRFY(addr)) => {
+                            Ok(Command::MAIL(from, parameters)) => {
+                                // Authenticated users may be limited in who they can send as.
+                                let identity = authenticated.as_ref().or(certificate.as_ref());
+                                let allowed = identity
+                                    .and_then(|username| settings.user(username))
+                                    .is_none_or(|user| user.may_send_from(&from));
+
+                                if transaction != Transaction::Idle {
+                                    // A transaction is already underway, it must be reset first.
+                                    errors += 1;
+                                    respond(&mut stream, Response::_503_BadSequence).await?;
+                                } else if !allowed {
+                                    errors += 1;
+                                    respond(&mut stream, Response::_553_MailboxNameNotAllowed)
+                                        .await?;
+                                } else if settings.max_message_size > 0
+                                    && declared_size(&parameters)
+                                        .is_some_and(|size| size > settings.max_message_size)
+                                {
+                                    // No point receiving a message we already know is too big.
+                                    respond(&mut stream, Response::_552_MessageSizeExceeded)
+                                        .await?;
+                                } else {
+                                    message.from = Some(from);
+                                    message.from_parameters = parameters;
+                                    message.authenticated = identity.cloned();
+                                    transaction = Transaction::Sender;
+                                    respond(&mut stream, Response::_250_Completed("OK")).await?;
+                                }
+                            }
+                            Ok(Command::RCPT(to, parameters)) => {
+                                if transaction == Transaction::Idle {
+                                    errors += 1;
+                                    respond(&mut stream, Response::_503_BadSequence).await?;
+                                } else {
+                                    message.to.push(to);
+                                    message.to_parameters.push(parameters);
+                                    transaction = Transaction::Recipients;
+                                    respond(&mut stream, Response::_250_Completed("OK")).await?;
+                                }
+                            }
+                            Ok(Command::AUTH(mechanism, initial_response)) => {
+                                // AUTH is an ESMTP extension, so only allowed after an EHLO,
+                                // and not once authenticated or in the middle of a transaction.
+                                if !extended
+                                    || authenticated.is_some()
+                                    || transaction != Transaction::Idle
+                                {
+                                    errors += 1;
+                                    respond(&mut stream, Response::_503_BadSequence).await?;
+                                } else if settings.require_tls_for_auth && !encrypted {
+                                    errors += 1;
+                                    respond(&mut stream, Response::_538_EncryptionRequired).await?;
+                                } else {
+                                    authenticated = authentication(
+                                        &mut stream,
+                                        settings,
+                                        &mechanism,
+                                        initial_response,
+                                        certificate.as_deref(),
+                                    )
+                                    .await?;
+                                    if authenticated.is_none() {
+                                        errors += 1;
+                                    }
+                                }
+                            }
+                            Ok(Command::STARTTLS) => match tls {
+                                Tls::Upgrade(acceptor)
+                                    if extended
+                                        && !encrypted
+                                        && transaction == Transaction::Idle =>
+                                {
+                                    respond(&mut stream, Response::_220_ReadyToStartTls).await?;
+
+                                    // Anything the client sent before the handshake is thrown
+                                    // away along with the framing, RFC 3207 section 4.2.
+                                    let (encrypted_stream, info) =
+                                        encrypt(acceptor, stream.into_inner(), settings).await?;
+                                    stream = encrypted_stream;
+                                    encrypted = true;
+                                    certificate = info.client_certificate.clone();
+                                    tls_info = Some(info);
+
+                                    // The session starts over, the client must greet us again.
+                                    message = Message::new();
+                                    transaction = Transaction::Idle;
+                                    extended = false;
+                                    authenticated = None;
+                                    state = State::ReceiveGreeting;
+                                }
+                                Tls::Upgrade(_) | Tls::Implicit(_) => {
+                                    // Either already encrypted, or the wrong time to do it.
+                                    errors += 1;
+                                    respond(&mut stream, Response::_503_BadSequence).await?;
+                                }
+                                Tls::Disabled => {
+                                    errors += 1;
+                                    respond(&mut stream, Response::_502_CommandNotImplemented)
+                                        .await?;
+                                }
+                            },
+                            Ok(Command::VRFY(addr)) => {
                                 // Currently we verify all addresses as ok..
                                 respond(&mut stream, Response::_250_Completed(&addr)).await?;
                             }
-                            Some(Command::DATA) => {
-                                respond(&mut stream, Response::_354_StartMailInput).await?;
-                                state = State::AcceptData;
+                            Ok(Command::DATA) => match transaction {
+                                Transaction::Idle => {
+                                    errors += 1;
+                                    respond(&mut stream, Response::_503_BadSequence).await?;
+                                }
+                                Transaction::Sender => {
+                                    errors += 1;
+                                    respond(
+                                        &mut stream,
+                                        Response::_554_TransactionFailed("No valid recipients"),
+                                    )
+                                    .await?;
+                                }
+                                Transaction::Recipients => {
+                                    respond(&mut stream, Response::_354_StartMailInput).await?;
+                                    state = State::AcceptData;
+                                }
+                            },
+                            Ok(Command::RSET) => {
+                                // Abandon the current transaction, but keep the session going.
+                                message = Message::new();
+                                transaction = Transaction::Idle;
+                                respond(&mut stream, Response::_250_Completed("OK")).await?;
+                            }
+                            Ok(Command::NOOP) => {
+                                respond(&mut stream, Response::_250_Completed("OK")).await?;
                             }
-                            Some(Command::QUIT) => {
+                            Ok(Command::QUIT) => {
                                 respond(&mut stream, Response::_221_ServiceClosing).await?;
                                 state = State::End;
                             }
-                            _ => {
-                                respond(&mut stream, Response::_503_BadSequence).await?;
-                                state = State::Rejected;
+                            Err(err) => {
+                                errors += 1;
+                                respond(&mut stream, parse_error_response(err)).await?;
                             }
                         }
                     }
@@ -190,26 +712,81 @@ pub async fn converse<T: AsyncRead + AsyncWrite + Unpin>(
             State::AcceptData => {
                 // In this state we are getting the main body text of the email, one line at a time.
                 // When we get a "." by itself we are finished.
+                // The data is kept exactly as it was sent, other than bare LFs becoming CRLF.
                 match stream.next().await {
-                    Some(msg) => {
-                        let msg = msg?;
-                        if msg == "." {
-                            respond(&mut stream, Response::_250_Completed("OK")).await?;
+                    Some(msg) => match msg? {
+                        Line::Text(msg) if codec::trim_ending(&msg) == b"." => {
+                            // The transaction is complete, pass the message on and start afresh.
+                            transaction = Transaction::Idle;
+                            let mut completed = mem::replace(&mut message, Message::new());
+                            completed.peer = peer;
+                            completed.received = Utc::now();
+                            completed.helo = helo.clone();
+                            completed.protocol =
+                                protocol(extended, encrypted, completed.authenticated.is_some());
+                            completed.received_by = settings.domain.clone();
+                            completed.tls = tls_info.clone();
+                            if oversized {
+                                oversized = false;
+                                respond(&mut stream, Response::_552_ExceededStorageAllocation)
+                                    .await?;
+                            } else {
+                                match deliver(completed).await {
+                                    Ok(()) => {
+                                        respond(&mut stream, Response::_250_Completed("OK")).await?
+                                    }
+                                    Err(err) => {
+                                        eprintln!("Failed to deliver message {}", err);
+                                        respond(&mut stream, delivery_error_response(&err))
+                                            .await?
+                                    }
+                                }
+                            }
                             state = State::Accept;
-                        } else {
-                            message.data.push(msg);
                         }
-                    }
+                        Line::Text(msg) => {
+                            if !oversized {
+                                // Lines starting with a "." have had another added,
+                                // RFC 5321 4.5.2.
+                                let line = msg.strip_prefix(b".").unwrap_or(&msg);
+                                // A bare LF ends the line in CRLF, as our headers do.
+                                match line.strip_suffix(b"\n") {
+                                    Some(text) if !text.ends_with(b"\r") => {
+                                        message.data.extend_from_slice(text);
+                                        message.data.extend_from_slice(b"\r\n");
+                                    }
+                                    _ => message.data.extend_from_slice(line),
+                                }
+                                // Too big, we still need to read to the end of the data but
+                                // we won't be keeping any of it.
+                                if settings.max_message_size > 0
+                                    && message.size() > settings.max_message_size
+                                {
+                                    oversized = true;
+                                    message.data.clear();
+                                }
+                            }
+                        }
+                        // A line too long to keep means the message is refused as too big.
+                        Line::TooLong => {
+                            oversized = true;
+                            message.data.clear();
+                        }
+                    },
                     None => return Err(Box::new(ConnectionError))
                 }
             }
 
             State::Rejected => {
-                stream.send("Error".to_string()).await?;
+                respond(&mut stream, Response::_421_ServiceNotAvailable(&settings.domain)).await?;
                 state = State::End;
             }
 
-            State::End => return Ok(message)
+            State::End => {
+                // Shut the stream down cleanly, so TLS clients get their close notify.
+                SinkExt::<String>::close(&mut stream).await?;
+                return Ok(());
+            }
         }
     }
 }
@@ -217,11 +794,105 @@ pub async fn converse<T: AsyncRead + AsyncWrite + Unpin>(
 #[cfg(test)]
 mod tests {
 
-    use crate::settings::Settings;
-    use crate::smtp::converse;
+    use crate::message::Message;
+    use crate::password;
+    use crate::settings::{Settings, User};
+    use crate::smtp::{converse, Tls};
+    use crate::tls;
+    use futures::{future, SinkExt};
+    use std::{fs, sync::Arc};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::prelude::*;
+    use tokio::stream::StreamExt;
+    use tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey};
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
     use tokio_test::{block_on, io};
     use tokio_util::codec::{Framed, LinesCodec};
 
+    /// Hold a conversation over the mock stream, returning the messages that were delivered.
+    fn converse_mock(stream: io::Mock, settings: &Settings) -> Vec<Message> {
+        let mut messages = Vec::new();
+        block_on(converse(stream, None, settings, Tls::Disabled, |message| {
+            messages.push(message);
+            future::ready(Ok(()))
+        }))
+        .unwrap();
+        messages
+    }
+
+    /// Greet the server with an EHLO, expecting the default extensions to be advertised.
+    fn ehlo(builder: &mut io::Builder) -> &mut io::Builder {
+        builder
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"EHLO ponk.com\n")
+            .write(b"250-groove.com, I hope this day finds you well.\n")
+            .write(b"250-PIPELINING\n")
+            .write(b"250-8BITMIME\n")
+            .write(b"250-SMTPUTF8\n")
+            .write(b"250-SIZE 10485760\n")
+            .write(b"250 AUTH PLAIN LOGIN CRAM-MD5\n")
+    }
+
+    /// Settings with TLS enabled, the self-signed certificate is kept in a temporary
+    /// directory named after the test.
+    fn tls_settings(test: &str) -> Settings {
+        Settings {
+            tls: true,
+            tls_dir: std::env::temp_dir().join(format!("smteepee-{}-{}", test, std::process::id())),
+            ..Settings::default()
+        }
+    }
+
+    /// A client that trusts our self-signed certificate, presenting a certificate of its
+    /// own if it is given one.
+    fn tls_client(
+        settings: &Settings,
+        certificate: Option<(Certificate, PrivateKey)>,
+    ) -> TlsConnector {
+        let cert = fs::read(settings.tls_dir.join(format!("{}.cert.pem", settings.domain))).unwrap();
+        let mut config = ClientConfig::new();
+        config.root_store.add_pem_file(&mut &cert[..]).unwrap();
+        if let Some((certificate, key)) = certificate {
+            config.set_single_client_cert(vec![certificate], key).unwrap();
+        }
+        TlsConnector::from(Arc::new(config))
+    }
+
+    /// Hold a conversation with the first client to connect, returning the messages it
+    /// delivered.
+    async fn serve(mut listener: TcpListener, settings: &Settings, tls: Tls<'_>) -> Vec<Message> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut messages = Vec::new();
+        converse(stream, None, settings, tls, |message| {
+            messages.push(message);
+            future::ready(Ok(()))
+        })
+        .await
+        .unwrap();
+        messages
+    }
+
+    /// Send a command from the client, returning the lines of the server's reply.
+    async fn command<T>(client: &mut Framed<T, LinesCodec>, command: &str) -> Vec<String>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        if !command.is_empty() {
+            client.send(command.to_string()).await.unwrap();
+        }
+
+        let mut lines = Vec::new();
+        loop {
+            let line = client.next().await.unwrap().unwrap();
+            let last = line.chars().nth(3) != Some('-');
+            lines.push(line);
+            if last {
+                return lines;
+            }
+        }
+    }
+
     #[test]
     fn test_greeting() {
         let stream = io::Builder::new()
@@ -231,8 +902,9 @@ mod tests {
             .read(b"QUIT\n")
             .write(b"221 Bye\n")
             .build();
-        let framed = Framed::new(stream, LinesCodec::new());
-        let _message = block_on(converse(framed, &Settings::default()));
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
     }
 
     #[test]
@@ -243,13 +915,18 @@ mod tests {
             .write(b"250 groove.com, I hope this day finds you well.\n")
             .read(b"MAIL FROM:<onk@ponk.com>\n")
             .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b".\n")
+            .write(b"250 OK\n")
             .read(b"QUIT\n")
             .write(b"221 Bye\n")
             .build();
-        let framed = Framed::new(stream, LinesCodec::new());
-        let message = block_on(converse(framed, &Settings::default()));
+        let messages = converse_mock(stream, &Settings::default());
 
-        assert_eq!(Some("onk@ponk.com".to_string()), message.unwrap().from);
+        assert_eq!(Some("onk@ponk.com".to_string()), messages[0].from);
     }
 
     #[test]
@@ -258,19 +935,726 @@ mod tests {
             .write(b"220 local ESMTP smteepee Service Ready\n")
             .read(b"HELO\n")
             .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"MAIL FROM:<ook@onk.com>\n")
+            .write(b"250 OK\n")
             .read(b"RCPT TO: <onk@ponk.com>\n")
             .write(b"250 OK\n")
             .read(b"RCPT TO:<pook@ook.co.uk>\n")
             .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b".\n")
+            .write(b"250 OK\n")
             .read(b"QUIT\n")
             .write(b"221 Bye\n")
             .build();
-        let framed = Framed::new(stream, LinesCodec::new());
-        let message = block_on(converse(framed, &Settings::default()));
+        let messages = converse_mock(stream, &Settings::default());
 
         assert_eq!(
             vec!["onk@ponk.com".to_string(), "pook@ook.co.uk".to_string()],
-            message.unwrap().to
+            messages[0].to
+        );
+    }
+
+    #[test]
+    fn test_delivery_failure() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"MAIL FROM:<ook@onk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b".\n")
+            .write(b"452 Requested action not taken: insufficient system storage\n")
+            .read(b"MAIL FROM:<ook@onk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b".\n")
+            .write(b"451 Requested action aborted: local error in processing\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let mut errors = vec![
+            std::io::ErrorKind::PermissionDenied,
+            std::io::ErrorKind::StorageFull,
+        ];
+        block_on(converse(stream, None, &Settings::default(), Tls::Disabled, |_| {
+            future::ready(Err(std::io::Error::from(errors.pop().unwrap()).into()))
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_data_is_preserved() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO ponk.com\r\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"MAIL FROM:<ook@onk.com>\r\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\r\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\r\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b"Subject: R\xe9sum\xe9 \r\n\r\n..\r\n.Dotted\r\nBare\n.\r\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\r\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert_eq!(
+            b"Subject: R\xe9sum\xe9 \r\n\r\n.\r\nDotted\r\nBare\r\n".to_vec(),
+            messages[0].data
         );
     }
+
+    #[test]
+    fn test_line_too_long() {
+        let long = format!("Subject: {}\r\n", "o".repeat(1000));
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO ponk.com\r\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(format!("MAIL FROM:<{}@onk.com>\r\n", "o".repeat(1000)).as_bytes())
+            .write(b"500 Line too long\n")
+            .read(b"MAIL FROM:<ook@onk.com>\r\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\r\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\r\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(long.as_bytes())
+            .read(b"\r\nOnk\r\n.\r\n")
+            .write(b"552 Requested mail action aborted: exceeded storage allocation\n")
+            .read(b"QUIT\r\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_multiple_transactions() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b"First\n.\n")
+            .write(b"250 OK\n")
+            .read(b"MAIL FROM:<ook@onk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<ponk@pook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b"Second\n.\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert_eq!(2, messages.len());
+        assert_eq!(Some("onk@ponk.com".to_string()), messages[0].from);
+        assert_eq!(vec!["pook@ook.co.uk".to_string()], messages[0].to);
+        assert_eq!(b"First\r\n".to_vec(), messages[0].data);
+        assert_eq!(Some("ook@onk.com".to_string()), messages[1].from);
+        assert_eq!(vec!["ponk@pook.co.uk".to_string()], messages[1].to);
+        assert_eq!(b"Second\r\n".to_vec(), messages[1].data);
+    }
+
+    #[test]
+    fn test_rset() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"RSET\n")
+            .write(b"250 OK\n")
+            .read(b"NOOP\n")
+            .write(b"250 OK\n")
+            .read(b"MAIL FROM:<ook@onk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<ponk@pook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b".\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert_eq!(1, messages.len());
+        assert_eq!(Some("ook@onk.com".to_string()), messages[0].from);
+        assert_eq!(vec!["ponk@pook.co.uk".to_string()], messages[0].to);
+    }
+
+    #[test]
+    fn test_greeting_again() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO ponk.com\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"EHLO onk.com\n")
+            .write(b"250-groove.com, I hope this day finds you well.\n")
+            .write(b"250-PIPELINING\n")
+            .write(b"250-8BITMIME\n")
+            .write(b"250-SMTPUTF8\n")
+            .write(b"250-SIZE 10485760\n")
+            .write(b"250 AUTH PLAIN LOGIN CRAM-MD5\n")
+            .read(b"DATA\n")
+            .write(b"503 Bad sequence of commands\n")
+            .read(b"MAIL FROM:<ook@onk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<ponk@pook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"HELO ook.com\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"MAIL FROM:<ook@onk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<ponk@pook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b".\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert_eq!(1, messages.len());
+        assert_eq!(vec!["ponk@pook.co.uk".to_string()], messages[0].to);
+        assert_eq!(Some("ook.com".to_string()), messages[0].helo);
+        assert_eq!("SMTP", messages[0].protocol);
+    }
+
+    #[test]
+    fn test_bad_sequence() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"503 Bad sequence of commands\n")
+            .read(b"DATA\n")
+            .write(b"503 Bad sequence of commands\n")
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"MAIL FROM:<ook@onk.com>\n")
+            .write(b"503 Bad sequence of commands\n")
+            .read(b"DATA\n")
+            .write(b"554 No valid recipients\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b".\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert_eq!(1, messages.len());
+        assert_eq!(Some("onk@ponk.com".to_string()), messages[0].from);
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"WIBBLE\n")
+            .write(b"500 Syntax error, command unrecognized\n")
+            .read(b"MAIL FROM:onk@ponk.com\n")
+            .write(b"501 Syntax error in parameters or arguments\n")
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_error_limit() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"WIBBLE\n")
+            .write(b"500 Syntax error, command unrecognized\n")
+            .read(b"DATA\n")
+            .write(b"503 Bad sequence of commands\n")
+            .write(b"421 groove.com Service not available, closing transmission channel\n")
+            .build();
+        let settings = Settings {
+            max_errors: 2,
+            ..Settings::default()
+        };
+        let messages = converse_mock(stream, &settings);
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_ehlo() {
+        let stream = ehlo(&mut io::Builder::new())
+            .read(b"AUTH PLAIN\n")
+            .write(b"334 \n")
+            .read(b"AHVzZXIAcGFzc3dvcmQ=\n")
+            .write(b"235 Authentication successful\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_message_too_big() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b"A rather long line\nAnd another\n.\n")
+            .write(b"552 Requested mail action aborted: exceeded storage allocation\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let settings = Settings {
+            max_message_size: 16,
+            ..Settings::default()
+        };
+        let messages = converse_mock(stream, &settings);
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_declared_size_too_big() {
+        let stream = ehlo(&mut io::Builder::new())
+            .read(b"MAIL FROM:<onk@ponk.com> SIZE=10485761\n")
+            .write(b"552 Message size exceeds fixed maximum message size\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"503 Bad sequence of commands\n")
+            .read(b"MAIL FROM:<onk@ponk.com> BODY=8BITMIME size=10485760\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_ehlo_without_auth() {
+        let stream = ehlo(&mut io::Builder::new())
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_require_auth() {
+        let stream = ehlo(&mut io::Builder::new())
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"530 Authentication required\n")
+            .read(b"AUTH PLAIN\n")
+            .write(b"334 \n")
+            .read(b"AHVzZXIAcGFzc3dvcmQ=\n")
+            .write(b"235 Authentication successful\n")
+            .read(b"AUTH PLAIN\n")
+            .write(b"503 Bad sequence of commands\n")
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let settings = Settings {
+            require_auth: true,
+            ..Settings::default()
+        };
+        let messages = converse_mock(stream, &settings);
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_auth_after_helo() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"HELO\n")
+            .write(b"250 groove.com, I hope this day finds you well.\n")
+            .read(b"AUTH PLAIN\n")
+            .write(b"503 Bad sequence of commands\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_auth_plain_initial_response() {
+        let stream = ehlo(&mut io::Builder::new())
+            .read(b"AUTH PLAIN AHVzZXIAd3Jvbmc=\n")
+            .write(b"535 Failed Authentication\n")
+            .read(b"AUTH PLAIN\n")
+            .write(b"334 \n")
+            .read(b"*\n")
+            .write(b"501 Syntax error in parameters or arguments\n")
+            .read(b"AUTH PLAIN dXNlcgB1c2VyAHBhc3N3b3Jk\n")
+            .write(b"235 Authentication successful\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_auth_login() {
+        let stream = ehlo(&mut io::Builder::new())
+            .read(b"AUTH LOGIN\n")
+            .write(b"334 VXNlcm5hbWU6\n")
+            .read(b"dXNlcg==\n")
+            .write(b"334 UGFzc3dvcmQ6\n")
+            .read(b"d3Jvbmc=\n")
+            .write(b"535 Failed Authentication\n")
+            .read(b"AUTH LOGIN dXNlcg==\n")
+            .write(b"334 UGFzc3dvcmQ6\n")
+            .read(b"cGFzc3dvcmQ=\n")
+            .write(b"235 Authentication successful\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_auth_unknown_mechanism() {
+        let stream = ehlo(&mut io::Builder::new())
+            .read(b"AUTH GSSAPI\n")
+            .write(b"504 Command parameter not implemented\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_cram_md5_with_hashed_passwords() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"EHLO ponk.com\n")
+            .write(b"250-groove.com, I hope this day finds you well.\n")
+            .write(b"250-PIPELINING\n")
+            .write(b"250-8BITMIME\n")
+            .write(b"250-SMTPUTF8\n")
+            .write(b"250-SIZE 10485760\n")
+            .write(b"250 AUTH PLAIN LOGIN\n")
+            .read(b"AUTH CRAM-MD5\n")
+            .write(b"504 Command parameter not implemented\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let settings = Settings {
+            users: vec![User::new("user", &password::hash("password").unwrap())],
+            ..Settings::default()
+        };
+        let messages = converse_mock(stream, &settings);
+
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_allowed_senders() {
+        let stream = ehlo(&mut io::Builder::new())
+            .read(b"AUTH PLAIN AG9vawBwb29r\n")
+            .write(b"235 Authentication successful\n")
+            .read(b"MAIL FROM:<onk@ponk.com>\n")
+            .write(b"553 Requested action not taken: mailbox name not allowed\n")
+            .read(b"MAIL FROM:<ook@onk.com>\n")
+            .write(b"250 OK\n")
+            .read(b"RCPT TO:<pook@ook.co.uk>\n")
+            .write(b"250 OK\n")
+            .read(b"DATA\n")
+            .write(b"354 End data with <CR><LF>.<CR><LF>\n")
+            .read(b".\n")
+            .write(b"250 OK\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let settings = Settings {
+            users: vec![
+                User::new("user", "password"),
+                User {
+                    allowed_senders: vec!["ook@onk.com".to_string()],
+                    ..User::new("ook", "pook")
+                },
+            ],
+            ..Settings::default()
+        };
+        let messages = converse_mock(stream, &settings);
+
+        assert_eq!(Some("ook".to_string()), messages[0].authenticated);
+    }
+
+    #[test]
+    fn test_starttls_unavailable() {
+        let stream = ehlo(&mut io::Builder::new())
+            .read(b"STARTTLS\n")
+            .write(b"502 Command not implemented\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let messages = converse_mock(stream, &Settings::default());
+
+        assert!(messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_starttls() {
+        let settings = Settings {
+            require_tls_for_auth: true,
+            ..tls_settings("starttls")
+        };
+        let acceptor = tls::acceptor(&settings).unwrap().unwrap();
+        let connector = tls_client(&settings, None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(listener, &settings, Tls::Upgrade(&acceptor));
+
+        let client = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut client = Framed::new(stream, LinesCodec::new());
+            command(&mut client, "").await;
+            let extensions = command(&mut client, "EHLO ponk.com").await;
+            assert!(extensions.contains(&"250-STARTTLS".to_string()));
+            assert!(!extensions.iter().any(|extension| extension.contains("AUTH")));
+            assert_eq!(
+                vec!["220 Ready to start TLS".to_string()],
+                command(&mut client, "STARTTLS").await
+            );
+
+            let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let stream = connector.connect(domain, client.into_inner()).await.unwrap();
+            let mut client = Framed::new(stream, LinesCodec::new());
+
+            // Once encrypted STARTTLS is no longer offered.
+            let extensions = command(&mut client, "EHLO ponk.com").await;
+            assert!(!extensions.contains(&"250-STARTTLS".to_string()));
+            assert!(extensions.iter().any(|extension| extension.contains("AUTH")));
+            assert_eq!(
+                vec!["235 Authentication successful".to_string()],
+                command(&mut client, "AUTH PLAIN AHVzZXIAcGFzc3dvcmQ=").await
+            );
+            command(&mut client, "MAIL FROM:<onk@ponk.com>").await;
+            command(&mut client, "RCPT TO:<pook@ook.co.uk>").await;
+            command(&mut client, "DATA").await;
+            client.send("Secret".to_string()).await.unwrap();
+            assert_eq!(vec!["250 OK".to_string()], command(&mut client, ".").await);
+            command(&mut client, "QUIT").await;
+        };
+
+        let (messages, ()) = future::join(server, client).await;
+        fs::remove_dir_all(&settings.tls_dir).unwrap();
+
+        assert_eq!(1, messages.len());
+        assert_eq!(b"Secret\r\n".to_vec(), messages[0].data);
+        assert_eq!("ESMTPSA", messages[0].protocol);
+    }
+
+    #[tokio::test]
+    async fn test_implicit_tls() {
+        let settings = Settings {
+            require_tls_for_auth: true,
+            ..tls_settings("implicit")
+        };
+        let acceptor = tls::acceptor(&settings).unwrap().unwrap();
+        let connector = tls_client(&settings, None);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(listener, &settings, Tls::Implicit(&acceptor));
+
+        let client = async {
+            // The handshake comes first, the greeting is sent encrypted.
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let stream = connector.connect(domain, stream).await.unwrap();
+            let mut client = Framed::new(stream, LinesCodec::new());
+            assert_eq!(
+                Some("220 local ESMTP smteepee Service Ready".to_string()),
+                client.next().await.map(Result::unwrap)
+            );
+
+            // The connection is already encrypted, so AUTH is offered and STARTTLS isn't.
+            let extensions = command(&mut client, "EHLO ponk.com").await;
+            assert!(!extensions.contains(&"250-STARTTLS".to_string()));
+            assert!(extensions.iter().any(|extension| extension.contains("AUTH")));
+            assert_eq!(
+                vec!["503 Bad sequence of commands".to_string()],
+                command(&mut client, "STARTTLS").await
+            );
+            command(&mut client, "MAIL FROM:<onk@ponk.com>").await;
+            command(&mut client, "RCPT TO:<pook@ook.co.uk>").await;
+            command(&mut client, "DATA").await;
+            client.send("Secret".to_string()).await.unwrap();
+            assert_eq!(vec!["250 OK".to_string()], command(&mut client, ".").await);
+            command(&mut client, "QUIT").await;
+        };
+
+        let (messages, ()) = future::join(server, client).await;
+        fs::remove_dir_all(&settings.tls_dir).unwrap();
+
+        assert_eq!(1, messages.len());
+        assert_eq!("ESMTPS", messages[0].protocol);
+        let tls = messages[0].tls.as_ref().unwrap();
+        assert!(tls.version.starts_with("TLS"));
+        assert_eq!(None, tls.client_certificate);
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate() {
+        let settings = tls_settings("mtls");
+        let dir = settings.tls_dir.clone();
+        fs::create_dir_all(&dir).unwrap();
+
+        // A CA that has signed a certificate for "user".
+        let mut params = rcgen::CertificateParams::new(vec!["ca.groove.com".to_string()]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+        let mut params = rcgen::CertificateParams::new(vec!["ponk.com".to_string()]);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params.distinguished_name.push(rcgen::DnType::CommonName, "user");
+        let client_cert = rcgen::Certificate::from_params(params).unwrap();
+
+        let settings = Settings {
+            tls_client_ca: Some(dir.join("ca.pem")),
+            require_auth: true,
+            ..settings
+        };
+        let acceptor = tls::acceptor(&settings).unwrap().unwrap();
+        let connector = tls_client(
+            &settings,
+            Some((
+                Certificate(client_cert.serialize_der_with_signer(&ca).unwrap()),
+                PrivateKey(client_cert.serialize_private_key_der()),
+            )),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(listener, &settings, Tls::Implicit(&acceptor));
+
+        let client = async {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let stream = connector.connect(domain, stream).await.unwrap();
+            let mut client = Framed::new(stream, LinesCodec::new());
+            command(&mut client, "").await;
+            let extensions = command(&mut client, "EHLO ponk.com").await;
+            assert!(extensions.contains(&"250 AUTH PLAIN LOGIN CRAM-MD5 EXTERNAL".to_string()));
+
+            // The certificate is enough to send mail without authenticating.
+            assert_eq!(
+                vec!["250 OK".to_string()],
+                command(&mut client, "MAIL FROM:<onk@ponk.com>").await
+            );
+            command(&mut client, "RSET").await;
+            assert_eq!(
+                vec!["235 Authentication successful".to_string()],
+                command(&mut client, "AUTH EXTERNAL =").await
+            );
+            command(&mut client, "MAIL FROM:<onk@ponk.com>").await;
+            command(&mut client, "RCPT TO:<pook@ook.co.uk>").await;
+            command(&mut client, "DATA").await;
+            client.send("Signed".to_string()).await.unwrap();
+            assert_eq!(vec!["250 OK".to_string()], command(&mut client, ".").await);
+            command(&mut client, "QUIT").await;
+        };
+
+        let (messages, ()) = future::join(server, client).await;
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(1, messages.len());
+        assert_eq!(Some("user".to_string()), messages[0].authenticated);
+    }
+
+    #[test]
+    fn test_require_tls_for_auth() {
+        let stream = io::Builder::new()
+            .write(b"220 local ESMTP smteepee Service Ready\n")
+            .read(b"EHLO ponk.com\n")
+            .write(b"250-groove.com, I hope this day finds you well.\n")
+            .write(b"250-PIPELINING\n")
+            .write(b"250-8BITMIME\n")
+            .write(b"250-SMTPUTF8\n")
+            .write(b"250 SIZE 10485760\n")
+            .read(b"AUTH PLAIN AHVzZXIAcGFzc3dvcmQ=\n")
+            .write(b"538 Encryption required for requested authentication mechanism\n")
+            .read(b"QUIT\n")
+            .write(b"221 Bye\n")
+            .build();
+        let settings = Settings {
+            require_tls_for_auth: true,
+            ..Settings::default()
+        };
+        let messages = converse_mock(stream, &settings);
+
+        assert!(messages.is_empty());
+    }
 }
@@ -0,0 +1,455 @@
+use crate::message::Message;
+use crate::settings::{self, Envelope, Format};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, ErrorCode};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use std::io::Write;
+use tokio::io::{self, AsyncWriteExt};
+use std::sync;
+use tokio::sync::Mutex;
+use tokio::task;
+
+/// Somewhere to keep the messages we have received.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store the message, failing if it can't be kept safely.
+    async fn store(&self, message: &Message) -> io::Result<()>;
+}
+
+/// Write the data to a new file, failing rather than overwrite an existing one.
+async fn write_new(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await?;
+    file.write_all(data).await?;
+    file.sync_all().await
+}
+
+/// The message as it is stored, with the envelope headers following the trace headers
+/// if they are wanted.
+fn contents(message: &Message, envelope: Envelope) -> Vec<u8> {
+    let mut headers = vec![message.return_path_header()];
+    headers.extend(message.received_header());
+    if envelope == Envelope::Header {
+        headers.extend(message.envelope_headers());
+    }
+    message.with_headers(&headers)
+}
+
+/// Each message is written to its own .eml file in the directory,
+/// with the envelope in a .json file of the same name if it is kept in a sidecar.
+pub struct Eml {
+    path: PathBuf,
+    envelope: Envelope,
+}
+
+impl Eml {
+    pub fn new(path: PathBuf, envelope: Envelope) -> io::Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        Ok(Eml { path, envelope })
+    }
+}
+
+#[async_trait]
+impl Storage for Eml {
+    async fn store(&self, message: &Message) -> io::Result<()> {
+        let name = &message.id;
+        if self.envelope == Envelope::Sidecar {
+            let path = self.path.join(format!("{}.json", name));
+            write_new(&path, message.envelope_json().as_bytes()).await?;
+        }
+
+        let path = self.path.join(format!("{}.eml", name));
+        write_new(&path, &contents(message, self.envelope)).await
+    }
+}
+
+/// A Maildir, messages are written to tmp and then moved into new once they are complete,
+/// so readers never see a partial message.
+/// If the envelope is kept in a sidecar it goes in an "envelopes" directory alongside,
+/// named after the message.
+/// https://cr.yp.to/proto/maildir.html
+pub struct Maildir {
+    path: PathBuf,
+    hostname: String,
+    envelope: Envelope,
+}
+
+impl Maildir {
+    pub fn new(path: PathBuf, hostname: &str, envelope: Envelope) -> io::Result<Self> {
+        for dir in &["tmp", "new", "cur"] {
+            std::fs::create_dir_all(path.join(dir))?;
+        }
+        if envelope == Envelope::Sidecar {
+            std::fs::create_dir_all(path.join("envelopes"))?;
+        }
+
+        // The hostname can't contain the characters Maildir uses as separators.
+        let hostname = hostname.replace('/', "\\057").replace(':', "\\072");
+        Ok(Maildir {
+            path,
+            hostname,
+            envelope,
+        })
+    }
+
+    /// The name for the message, "time.unique.hostname" with the message's id giving the
+    /// first two parts, so the file can be found from its Received header.
+    fn unique_name(&self, message: &Message) -> String {
+        format!("{}.{}", message.id, self.hostname)
+    }
+}
+
+#[async_trait]
+impl Storage for Maildir {
+    async fn store(&self, message: &Message) -> io::Result<()> {
+        let name = self.unique_name(message);
+        let tmp = self.path.join("tmp").join(&name);
+
+        // The envelope is written first, so it is there as soon as the message appears.
+        if self.envelope == Envelope::Sidecar {
+            let path = self.path.join("envelopes").join(format!("{}.json", name));
+            write_new(&path, message.envelope_json().as_bytes()).await?;
+        }
+
+        if let Err(err) = write_new(&tmp, &contents(message, self.envelope)).await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(err);
+        }
+        fs::rename(&tmp, self.path.join("new").join(&name)).await
+    }
+}
+
+/// Messages are appended to mbox files in the mboxrd format, either a file per recipient
+/// named after their address, or a single file called "mbox".
+pub struct Mbox {
+    path: PathBuf,
+    single_file: bool,
+    // Appends from different connections mustn't interleave.
+    lock: Mutex<()>,
+}
+
+impl Mbox {
+    pub fn new(path: PathBuf, single_file: bool) -> io::Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        Ok(Mbox {
+            path,
+            single_file,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// The file a recipient's mail is appended to.
+    /// Their address is made safe to use as a file name.
+    fn mailbox(&self, recipient: &str) -> PathBuf {
+        if self.single_file {
+            return self.path.join("mbox");
+        }
+
+        let mut name: String = recipient
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' | '@' | '.' | '_' | '+' | '-' => c,
+                _ => '_',
+            })
+            .collect();
+        if name.is_empty() || name.starts_with('.') {
+            name.insert(0, '_');
+        }
+        self.path.join(name)
+    }
+
+    /// Append the entry to the file, if it fails the file is truncated back so it
+    /// isn't left with part of a message.
+    /// The file is locked with flock while we append, so mbox readers don't see a
+    /// partial entry.
+    async fn append(path: &Path, entry: &[u8]) -> io::Result<()> {
+        let path = path.to_path_buf();
+        let entry = entry.to_vec();
+        task::spawn_blocking(move || {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)?;
+            file.lock()?;
+            let length = file.metadata()?.len();
+
+            let result = file.write_all(&entry).and_then(|()| file.sync_all());
+            if result.is_err() {
+                let _ = file.set_len(length);
+            }
+            result
+        })
+        .await?
+    }
+}
+
+/// Format the message as an mboxrd entry, the envelope is always kept in its headers
+/// with only the recipients, by their index, whose mailbox the entry is for.
+/// It starts with a "From " line giving the sender and the time it was received,
+/// any line in the message matching /^>*From / is quoted with a further ">",
+/// and a blank line follows it.
+/// The message's own line endings are kept, so the From line and blank line end in CRLF
+/// to match.
+fn mboxrd(message: &Message, recipients: &[usize]) -> Vec<u8> {
+    // Readers split the From line on spaces, so the sender can't have any.
+    let sender = match message.from.as_deref() {
+        Some("") | None => "MAILER-DAEMON".to_string(),
+        Some(from) => from.replace(char::is_whitespace, "_"),
+    };
+    let mut entry = format!("From {} {}\r\n", sender, message.received.format("%a %b %e %T %Y"))
+        .into_bytes();
+
+    let mut headers = vec![message.return_path_header()];
+    headers.extend(message.received_header());
+    headers.extend(message.envelope_headers_for(|idx| recipients.contains(&idx)));
+    let contents = message.with_headers(&headers);
+    for line in contents.split_inclusive(|b| *b == b'\n') {
+        let unquoted = line.iter().position(|b| *b != b'>').unwrap_or(line.len());
+        if line[unquoted..].starts_with(b"From ") {
+            entry.push(b'>');
+        }
+        entry.extend_from_slice(line);
+    }
+    entry.extend_from_slice(b"\r\n");
+    entry
+}
+
+#[async_trait]
+impl Storage for Mbox {
+    async fn store(&self, message: &Message) -> io::Result<()> {
+        // Each mailbox gets its own entry, naming only the recipients delivered to it.
+        let mut mailboxes: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (idx, to) in message.to.iter().enumerate() {
+            mailboxes.entry(self.mailbox(to)).or_default().push(idx);
+        }
+
+        let _lock = self.lock.lock().await;
+        for (mailbox, recipients) in mailboxes {
+            Mbox::append(&mailbox, &mboxrd(message, &recipients)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The tables and indexes in the SQLite database.
+/// The recipients are in their own table so that mail to an address can be found quickly.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        received TEXT NOT NULL,
+        sender TEXT,
+        subject TEXT,
+        message_id TEXT,
+        client_ip TEXT,
+        authenticated TEXT,
+        envelope TEXT NOT NULL,
+        body BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS recipients (
+        message INTEGER NOT NULL REFERENCES messages(id),
+        address TEXT NOT NULL COLLATE NOCASE
+    );
+    CREATE INDEX IF NOT EXISTS messages_received ON messages(received);
+    CREATE INDEX IF NOT EXISTS messages_sender ON messages(sender COLLATE NOCASE);
+    CREATE INDEX IF NOT EXISTS messages_subject ON messages(subject);
+    CREATE INDEX IF NOT EXISTS messages_message_id ON messages(message_id);
+    CREATE INDEX IF NOT EXISTS messages_client_ip ON messages(client_ip);
+    CREATE INDEX IF NOT EXISTS messages_authenticated ON messages(authenticated);
+    CREATE INDEX IF NOT EXISTS recipients_address ON recipients(address, message);
+";
+
+/// How long we wait for another connection to the database, such as someone reading
+/// the messages, to finish with it before giving up.
+const SQLITE_BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Convert an error from SQLite, a full disk can be reported to the client as such.
+fn sqlite_error(err: rusqlite::Error) -> io::Error {
+    let kind = match err.sqlite_error_code() {
+        Some(ErrorCode::DiskFull) => io::ErrorKind::StorageFull,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, err)
+}
+
+/// Messages are kept in a SQLite database, "messages.sqlite3" in the storage directory.
+/// The envelope is always kept as JSON in its own column.
+/// Along with the raw message the envelope and some headers are indexed so they can be
+/// queried, the received time is UTC as "YYYY-MM-DD HH:MM:SS.SSS" so SQLite's date
+/// functions can be used with it.
+pub struct Sqlite {
+    connection: sync::Arc<sync::Mutex<Connection>>,
+}
+
+impl Sqlite {
+    pub fn new(path: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        let connection = Connection::open(path.join("messages.sqlite3")).map_err(sqlite_error)?;
+        connection
+            .busy_timeout(SQLITE_BUSY_TIMEOUT)
+            .map_err(sqlite_error)?;
+        // With a write ahead log readers don't block us storing messages.
+        connection
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))
+            .map_err(sqlite_error)?;
+        connection.execute_batch(SCHEMA).map_err(sqlite_error)?;
+        Ok(Sqlite {
+            connection: sync::Arc::new(sync::Mutex::new(connection)),
+        })
+    }
+
+    fn insert(connection: &mut Connection, message: &Message) -> rusqlite::Result<()> {
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO messages
+                (received, sender, subject, message_id, client_ip, authenticated, envelope, body)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                message.received.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+                message.from,
+                message.header("Subject"),
+                message.header("Message-ID"),
+                message.peer.map(|peer| peer.ip().to_string()),
+                message.authenticated,
+                message.envelope_json(),
+                message.get_data(),
+            ],
+        )?;
+
+        let id = transaction.last_insert_rowid();
+        for to in &message.to {
+            transaction.execute(
+                "INSERT INTO recipients (message, address) VALUES (?1, ?2)",
+                params![id, to],
+            )?;
+        }
+        transaction.commit()
+    }
+}
+
+#[async_trait]
+impl Storage for Sqlite {
+    async fn store(&self, message: &Message) -> io::Result<()> {
+        // SQLite blocks, so it is kept off the connection's task.
+        let connection = self.connection.clone();
+        let message = message.clone();
+        task::spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| io::Error::other("The database lock is poisoned"))?;
+            Sqlite::insert(&mut connection, &message).map_err(sqlite_error)
+        })
+        .await?
+    }
+}
+
+/// Open the storage given in the settings, creating it if need be.
+/// The hostname is used to name Maildir files.
+pub fn open(storage: &settings::Storage, hostname: &str) -> io::Result<Box<dyn Storage>> {
+    let path = storage.path.clone();
+    Ok(match storage.format {
+        Format::Eml => Box::new(Eml::new(path, storage.envelope)?),
+        Format::Maildir => Box::new(Maildir::new(path, hostname, storage.envelope)?),
+        Format::Mbox => Box::new(Mbox::new(path, storage.single_file)?),
+        Format::Sqlite => Box::new(Sqlite::new(path)?),
+    })
+}
+
+#[tokio::test]
+async fn test_maildir() {
+    let path = std::env::temp_dir().join(format!("smteepee-maildir-{}", std::process::id()));
+    let maildir = Maildir::new(path.clone(), "groove.com", Envelope::Sidecar).unwrap();
+
+    let mut message = Message::new();
+    message.data = b"Subject: Ook\r\n\r\nOnk\r\n".to_vec();
+    maildir.store(&message).await.unwrap();
+    maildir.store(&Message::new()).await.unwrap();
+    // The name comes from the message's id, which an existing file already has.
+    assert!(maildir.store(&message).await.is_err());
+
+    let new: Vec<_> = std::fs::read_dir(path.join("new")).unwrap().collect();
+    assert_eq!(2, new.len());
+    assert_eq!(0, std::fs::read_dir(path.join("tmp")).unwrap().count());
+
+    let name = format!("{}.groove.com", message.id);
+    assert_eq!(
+        message.get_data(),
+        std::fs::read(path.join("new").join(&name)).unwrap()
+    );
+
+    let envelope = path.join("envelopes").join(format!("{}.json", name));
+    assert_eq!(
+        message.envelope_json(),
+        std::fs::read_to_string(envelope).unwrap()
+    );
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_mbox() {
+    let path = std::env::temp_dir().join(format!("smteepee-mbox-{}", std::process::id()));
+    let mbox = Mbox::new(path.clone(), false).unwrap();
+
+    let mut message = Message::new();
+    message.from = Some("ook@onk.com".to_string());
+    message.to = vec!["Pook@ook.co.uk".to_string(), "../onk".to_string()];
+    message.data = b"Subject: Ook\r\n\r\nFrom here\r\n>From there\r\nFromage\r\n".to_vec();
+    mbox.store(&message).await.unwrap();
+    mbox.store(&message).await.unwrap();
+
+    let mailbox = std::fs::read_to_string(path.join("pook@ook.co.uk")).unwrap();
+    let entries: Vec<_> = mailbox.split("\r\n\r\nFrom ook@onk.com ").collect();
+    assert_eq!(2, entries.len());
+    assert!(mailbox.starts_with("From ook@onk.com "));
+    assert!(mailbox.ends_with("\r\n>From here\r\n>>From there\r\nFromage\r\n\r\n"));
+    assert!(mailbox.contains("X-Envelope-To: <Pook@ook.co.uk>\r\n"));
+    assert!(!mailbox.contains("../onk"));
+
+    let onk = std::fs::read_to_string(path.join("_.._onk")).unwrap();
+    assert!(onk.contains("X-Envelope-To: <../onk>\r\n"));
+    assert!(!onk.contains("Pook@ook.co.uk"));
+
+    message.from = Some("o ok@onk.com".to_string());
+    assert!(mboxrd(&message, &[0]).starts_with(b"From o_ok@onk.com "));
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_sqlite() {
+    let path = std::env::temp_dir().join(format!("smteepee-sqlite-{}", std::process::id()));
+    let sqlite = Sqlite::new(path.clone()).unwrap();
+
+    let mut message = Message::new();
+    message.from = Some("ook@onk.com".to_string());
+    message.to = vec!["pook@ook.co.uk".to_string(), "onk@ponk.com".to_string()];
+    message.data = b"Subject: Ook\r\n\r\nOnk\r\n".to_vec();
+    message.peer = Some("192.0.2.1:1234".parse().unwrap());
+    sqlite.store(&message).await.unwrap();
+
+    let connection = Connection::open(path.join("messages.sqlite3")).unwrap();
+    let journal_mode: String = connection
+        .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!("wal", journal_mode);
+    let (subject, client_ip, body): (String, String, Vec<u8>) = connection
+        .query_row(
+            "SELECT subject, client_ip, body FROM messages
+             JOIN recipients ON recipients.message = messages.id
+             WHERE address = 'Pook@ook.co.uk' AND received >= datetime('now', '-1 hour')",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!("Ook", subject);
+    assert_eq!("192.0.2.1", client_ip);
+    assert_eq!(message.get_data(), body);
+
+    std::fs::remove_dir_all(path).unwrap();
+}
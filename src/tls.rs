@@ -0,0 +1,188 @@
+use crate::settings::Settings;
+use rcgen::{CertificateParams, DistinguishedName, DnType, SanType};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate,
+    NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Build an error for a PEM file that we couldn't make sense of.
+fn invalid_pem(path: &Path, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("No valid {} found in {}", what, path.display()),
+    )
+}
+
+/// Load the chain of certificates from a PEM file.
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(invalid_pem(path, "certificates")),
+    }
+}
+
+/// Load the private key from a PEM file, it can be either PKCS8 or RSA.
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if let Ok(mut keys) = pemfile::pkcs8_private_keys(&mut reader) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+
+    let mut reader = BufReader::new(File::open(path)?);
+    match pemfile::rsa_private_keys(&mut reader) {
+        Ok(mut keys) if !keys.is_empty() => Ok(keys.remove(0)),
+        _ => Err(invalid_pem(path, "private key")),
+    }
+}
+
+/// Create the server configuration, which decides how client certificates are verified.
+/// If a CA bundle is configured clients are asked for a certificate signed by one of
+/// those CAs, otherwise they aren't asked for one at all.
+fn server_config(settings: &Settings) -> io::Result<ServerConfig> {
+    match &settings.tls_client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|_| invalid_pem(path, "CA certificates"))?;
+            }
+
+            if settings.tls_client_cert_required {
+                Ok(ServerConfig::new(AllowAnyAuthenticatedClient::new(roots)))
+            } else {
+                Ok(ServerConfig::new(AllowAnyAnonymousOrAuthenticatedClient::new(roots)))
+            }
+        }
+        None => Ok(ServerConfig::new(NoClientAuth::new())),
+    }
+}
+
+/// The identity of the client given by their verified certificate.
+/// This is the common name of the subject, or the whole subject if it has no common name.
+pub fn certificate_identity(certs: &[Certificate]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&certs.first()?.0).ok()?;
+    let subject = cert.subject();
+    let common_name = subject
+        .iter_common_name()
+        .next()
+        .and_then(|common_name| common_name.as_str().ok());
+
+    match common_name {
+        Some(common_name) => Some(common_name.to_string()),
+        None => Some(subject.to_string()),
+    }
+}
+
+/// Generate a self-signed certificate for our domain, along with its private key.
+/// Both are PEM encoded.
+fn generate_self_signed(domain: &str) -> Result<(String, String), rcgen::RcgenError> {
+    let mut params = CertificateParams::new(vec![domain.to_string(), "localhost".to_string()]);
+    params
+        .subject_alt_names
+        .push(SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    params
+        .subject_alt_names
+        .push(SanType::IpAddress(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, domain);
+
+    let cert = rcgen::Certificate::from_params(params)?;
+    Ok((cert.serialize_pem()?, cert.serialize_private_key_pem()))
+}
+
+/// Write the private key so that only we can read it.
+/// Any old key is removed first, the mode is only given to a file as it is created.
+fn write_key(path: &Path, key: &str) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => (),
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    io::Write::write_all(&mut options.open(path)?, key.as_bytes())
+}
+
+/// Find the self-signed certificate and key for our domain in the TLS directory.
+/// They are generated the first time, after which they are reused so clients can pin the
+/// certificate.
+fn self_signed(settings: &Settings) -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
+    let cert_path = settings.tls_dir.join(format!("{}.cert.pem", settings.domain));
+    let key_path = settings.tls_dir.join(format!("{}.key.pem", settings.domain));
+
+    if !cert_path.exists() || !key_path.exists() {
+        let (cert, key) = generate_self_signed(&settings.domain)?;
+        fs::create_dir_all(&settings.tls_dir)?;
+        write_key(&key_path, &key)?;
+        fs::write(&cert_path, cert)?;
+        println!("Generated a self-signed certificate for {}", settings.domain);
+    }
+
+    println!("Clients can pin the certificate in {}", cert_path.display());
+    Ok((cert_path, key_path))
+}
+
+/// Create the acceptor used to encrypt connections with the certificate and key
+/// given in the settings. If they aren't given, but TLS is enabled, then a self-signed
+/// certificate is used. Otherwise TLS isn't available.
+pub fn acceptor(settings: &Settings) -> Result<Option<TlsAcceptor>, Box<dyn Error>> {
+    let (cert, key) = match (&settings.tls_cert, &settings.tls_key) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        (None, None) if settings.tls => self_signed(settings)?,
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Both tls_cert and tls_key must be given to enable TLS",
+            )))
+        }
+    };
+
+    let mut config = server_config(settings)?;
+    config.set_single_cert(load_certs(&cert)?, load_key(&key)?)?;
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+#[test]
+fn test_self_signed() {
+    let settings = Settings {
+        tls: true,
+        tls_dir: std::env::temp_dir().join(format!("smteepee-tls-{}", std::process::id())),
+        ..Settings::default()
+    };
+
+    assert!(acceptor(&settings).unwrap().is_some());
+    let cert = fs::read(settings.tls_dir.join("groove.com.cert.pem")).unwrap();
+
+    // The certificate is cached, not generated afresh each time.
+    assert!(acceptor(&settings).unwrap().is_some());
+    assert_eq!(cert, fs::read(settings.tls_dir.join("groove.com.cert.pem")).unwrap());
+
+    // Regenerating replaces a key that others could read with one they can't.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let key = settings.tls_dir.join("groove.com.key.pem");
+        fs::set_permissions(&key, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::remove_file(settings.tls_dir.join("groove.com.cert.pem")).unwrap();
+        assert!(acceptor(&settings).unwrap().is_some());
+        assert_eq!(0o600, fs::metadata(&key).unwrap().permissions().mode() & 0o777);
+    }
+
+    fs::remove_dir_all(&settings.tls_dir).unwrap();
+}